@@ -0,0 +1,447 @@
+use crate::app_config::AnkiConfig;
+use crate::connect::{MODEL_BACK, MODEL_CSS, MODEL_FIELDS, MODEL_FRONT, MODEL_NAME};
+use crate::note::{Note, to_html};
+use anyhow::{Context, Result};
+use rusqlite::{Connection, params};
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::io::{Seek, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use zip::ZipWriter;
+use zip::write::SimpleFileOptions;
+
+/// Schema of a collection in the legacy format (version 11), which every
+/// Anki client imports
+const SCHEMA: &str = "
+CREATE TABLE col (
+    id integer primary key, crt integer not null, mod integer not null,
+    scm integer not null, ver integer not null, dty integer not null,
+    usn integer not null, ls integer not null, conf text not null,
+    models text not null, decks text not null, dconf text not null,
+    tags text not null
+);
+CREATE TABLE notes (
+    id integer primary key, guid text not null, mid integer not null,
+    mod integer not null, usn integer not null, tags text not null,
+    flds text not null, sfld integer not null, csum integer not null,
+    flags integer not null, data text not null
+);
+CREATE TABLE cards (
+    id integer primary key, nid integer not null, did integer not null,
+    ord integer not null, mod integer not null, usn integer not null,
+    type integer not null, queue integer not null, due integer not null,
+    ivl integer not null, factor integer not null, reps integer not null,
+    lapses integer not null, left integer not null, odue integer not null,
+    odid integer not null, flags integer not null, data text not null
+);
+CREATE TABLE revlog (
+    id integer primary key, cid integer not null, usn integer not null,
+    ease integer not null, ivl integer not null, lastIvl integer not null,
+    factor integer not null, time integer not null, type integer not null
+);
+CREATE TABLE graves (usn integer not null, oid integer not null, type integer not null);
+CREATE INDEX ix_notes_usn ON notes (usn);
+CREATE INDEX ix_cards_usn ON cards (usn);
+CREATE INDEX ix_revlog_usn ON revlog (usn);
+CREATE INDEX ix_cards_nid ON cards (nid);
+CREATE INDEX ix_cards_sched ON cards (did, queue, due);
+CREATE INDEX ix_revlog_cid ON revlog (cid);
+CREATE INDEX ix_notes_csum ON notes (csum);
+";
+/// Id of the deck every collection starts with
+const DEFAULT_DECK_ID: i64 = 1;
+/// Separator of the field values in the `flds` column
+const FIELD_SEPARATOR: &str = "\x1f";
+
+/// Writes the notes as an Anki package: a collection holding the bundled
+/// "Kindle Highlight" note type, the configured decks and one card per
+/// note, and an empty media manifest. Note GUIDs derive from the clipping,
+/// so that importing a newer package updates the notes instead of
+/// duplicating them.
+pub fn write_apkg(notes: &[Note], config: &AnkiConfig, writer: impl Write + Seek) -> Result<usize> {
+    let collection = build_collection(notes, config)?;
+    let mut zip = ZipWriter::new(writer);
+    let options = SimpleFileOptions::default();
+    zip.start_file("collection.anki2", options)?;
+    zip.write_all(&collection)?;
+    zip.start_file("media", options)?;
+    zip.write_all(b"{}")?;
+    zip.finish()?;
+    Ok(notes.len())
+}
+
+/// Database file of the collection
+fn build_collection(notes: &[Note], config: &AnkiConfig) -> Result<Vec<u8>> {
+    let now_ms = i64::try_from(SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis())?;
+    let now = now_ms / 1000;
+    let model_id = stable_id(MODEL_NAME);
+    let decks: BTreeMap<String, i64> = notes
+        .iter()
+        .map(|note| {
+            let name = config.deck_name(note);
+            let id = stable_id(&name);
+            (name, id)
+        })
+        .chain([(config.deck.clone(), stable_id(&config.deck))])
+        .collect();
+
+    let mut conn = Connection::open_in_memory()?;
+    conn.execute_batch(SCHEMA)?;
+    let tx = conn.transaction()?;
+    tx.execute(
+        "INSERT INTO col VALUES (1, ?1, ?2, ?3, 11, 0, 0, 0, ?4, ?5, ?6, ?7, '{}')",
+        params![
+            now,
+            now_ms,
+            now_ms,
+            collection_conf().to_string(),
+            json!({ model_id.to_string(): model(model_id, stable_id(&config.deck), now) })
+                .to_string(),
+            deck_table(&decks, now).to_string(),
+            json!({ "1": deck_options() }).to_string(),
+        ],
+    )?;
+    for (index, note) in (0..).zip(notes) {
+        let id = now_ms + index;
+        let fields = [
+            note.title.clone(),
+            note.author.clone().unwrap_or_default(),
+            note.tidied_note.clone(),
+            note.comment.clone().unwrap_or_default(),
+            note.render("{location}"),
+            note.date().unwrap_or_default(),
+        ]
+        .map(|field| to_html(&field));
+        let tags = note.tags(&config.tags);
+        let tags = if tags.is_empty() {
+            String::new()
+        } else {
+            format!(" {} ", tags.join(" "))
+        };
+        let guid = note.stable_id().unwrap_or_else(|| note.content_hash());
+        tx.execute(
+            "INSERT INTO notes VALUES (?1, ?2, ?3, ?4, -1, ?5, ?6, ?7, ?8, 0, '')",
+            params![
+                id,
+                guid,
+                model_id,
+                now,
+                tags,
+                fields.join(FIELD_SEPARATOR),
+                note.title,
+                checksum(&note.title),
+            ],
+        )?;
+        tx.execute(
+            "INSERT INTO cards VALUES (?1, ?1, ?2, 0, ?3, -1, 0, 0, ?4, 0, 0, 0, 0, 0, 0, 0, 0, '')",
+            params![id, decks[&config.deck_name(note)], now, index + 1],
+        )?;
+    }
+    tx.commit()?;
+    let data = conn
+        .serialize("main")
+        .context("Failed to serialize the Anki collection")?;
+    Ok(data.to_vec())
+}
+
+/// Positive id derived from a name, so that the note type and decks keep
+/// their ids across packages
+fn stable_id(name: &str) -> i64 {
+    let digest = sha1_smol::Sha1::from(name).digest().bytes();
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    i64::from_be_bytes(bytes) & 0x001f_ffff_ffff_ffff
+}
+
+/// Duplicate check value of a note: the first 32 bits of the SHA-1 of its
+/// sort field
+fn checksum(sort_field: &str) -> i64 {
+    let digest = sha1_smol::Sha1::from(sort_field).digest().bytes();
+    i64::from(u32::from_be_bytes([
+        digest[0], digest[1], digest[2], digest[3],
+    ]))
+}
+
+fn collection_conf() -> Value {
+    json!({
+        "activeDecks": [DEFAULT_DECK_ID],
+        "curDeck": DEFAULT_DECK_ID,
+        "newSpread": 0,
+        "collapseTime": 1200,
+        "timeLim": 0,
+        "estTimes": true,
+        "dueCounts": true,
+        "curModel": null,
+        "nextPos": 1,
+        "sortType": "noteFld",
+        "sortBackwards": false,
+        "addToCur": true,
+    })
+}
+
+fn model(id: i64, deck_id: i64, now: i64) -> Value {
+    let fields: Vec<Value> = (0..)
+        .zip(MODEL_FIELDS)
+        .map(|(ord, name)| {
+            json!({
+                "name": name,
+                "ord": ord,
+                "sticky": false,
+                "rtl": false,
+                "font": "Arial",
+                "size": 20,
+                "media": [],
+            })
+        })
+        .collect();
+    json!({
+        "id": id,
+        "name": MODEL_NAME,
+        "type": 0,
+        "mod": now,
+        "usn": -1,
+        "sortf": 0,
+        "did": deck_id,
+        "tmpls": [{
+            "name": MODEL_NAME,
+            "ord": 0,
+            "qfmt": MODEL_FRONT,
+            "afmt": MODEL_BACK,
+            "bqfmt": "",
+            "bafmt": "",
+            "did": null,
+        }],
+        "flds": fields,
+        "css": MODEL_CSS,
+        "latexPre": "\\documentclass[12pt]{article}\n\\special{papersize=3in,5in}\n\\usepackage[utf8]{inputenc}\n\\usepackage{amssymb,amsmath}\n\\pagestyle{empty}\n\\setlength{\\parindent}{0in}\n\\begin{document}\n",
+        "latexPost": "\\end{document}",
+        "req": [[0, "any", [0]]],
+        "tags": [],
+        "vers": [],
+    })
+}
+
+/// Decks by id, including the default deck
+fn deck_table(decks: &BTreeMap<String, i64>, now: i64) -> Value {
+    let mut table = serde_json::Map::new();
+    for (name, id) in [("Default", DEFAULT_DECK_ID)]
+        .into_iter()
+        .chain(decks.iter().map(|(name, &id)| (name.as_str(), id)))
+    {
+        table.insert(
+            id.to_string(),
+            json!({
+                "id": id,
+                "name": name,
+                "mod": now,
+                "usn": -1,
+                "desc": "",
+                "dyn": 0,
+                "conf": 1,
+                "collapsed": false,
+                "extendNew": 10,
+                "extendRev": 50,
+                "newToday": [0, 0],
+                "revToday": [0, 0],
+                "lrnToday": [0, 0],
+                "timeToday": [0, 0],
+            }),
+        );
+    }
+    Value::Object(table)
+}
+
+/// Default deck options
+fn deck_options() -> Value {
+    json!({
+        "id": 1,
+        "name": "Default",
+        "mod": 0,
+        "usn": 0,
+        "maxTaken": 60,
+        "autoplay": true,
+        "timer": 0,
+        "replayq": true,
+        "dyn": false,
+        "new": {
+            "delays": [1, 10],
+            "ints": [1, 4, 7],
+            "initialFactor": 2500,
+            "order": 1,
+            "perDay": 20,
+            "bury": true,
+            "separate": true,
+        },
+        "lapse": {
+            "delays": [10],
+            "mult": 0,
+            "minInt": 1,
+            "leechFails": 8,
+            "leechAction": 0,
+        },
+        "rev": {
+            "perDay": 100,
+            "ease4": 1.3,
+            "fuzz": 0.05,
+            "minSpace": 1,
+            "ivlFct": 1,
+            "maxIvl": 36500,
+            "bury": true,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::note::Location;
+    use crate::note::fixtures::dune;
+    use std::io::{Cursor, Read};
+    use zip::ZipArchive;
+
+    /// Collection of a package, opened from its bytes
+    fn open_collection(package: Vec<u8>) -> Connection {
+        let mut archive = ZipArchive::new(Cursor::new(package)).unwrap();
+        let mut media = String::new();
+        archive
+            .by_name("media")
+            .unwrap()
+            .read_to_string(&mut media)
+            .unwrap();
+        assert_eq!(media, "{}");
+        let mut collection = Vec::new();
+        archive
+            .by_name("collection.anki2")
+            .unwrap()
+            .read_to_end(&mut collection)
+            .unwrap();
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.deserialize_read_exact("main", Cursor::new(&collection), collection.len(), false)
+            .unwrap();
+        conn
+    }
+
+    fn package(notes: &[Note], config: &AnkiConfig) -> Vec<u8> {
+        let mut buf = Cursor::new(Vec::new());
+        assert_eq!(write_apkg(notes, config, &mut buf).unwrap(), notes.len());
+        buf.into_inner()
+    }
+
+    #[test]
+    fn packages_notes_with_bundled_note_type() {
+        let mut commented = dune(100, "Fear is the mind-killer.");
+        commented.comment = Some("Litany".to_owned());
+        let config = AnkiConfig {
+            tags: vec!["kindle".to_owned(), "book::{title_slug}".to_owned()],
+            ..AnkiConfig::default()
+        };
+        let conn = open_collection(package(&[commented], &config));
+
+        let (models, decks): (String, String) = conn
+            .query_row("SELECT models, decks FROM col", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        let models: Value = serde_json::from_str(&models).unwrap();
+        let model = &models[stable_id(MODEL_NAME).to_string()];
+        assert_eq!(model["name"], MODEL_NAME);
+        assert_eq!(model["flds"][2]["name"], "Highlight");
+        let decks: Value = serde_json::from_str(&decks).unwrap();
+        assert_eq!(decks[stable_id("Kindle").to_string()]["name"], "Kindle");
+
+        let (flds, tags, sfld): (String, String, String) = conn
+            .query_row("SELECT flds, tags, sfld FROM notes", [], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .unwrap();
+        assert_eq!(
+            flds,
+            "Dune\x1fHerbert, Frank\x1fFear is the mind-killer.\x1fLitany\x1f100-102\x1f"
+        );
+        assert_eq!(tags, " kindle book::dune ");
+        assert_eq!(sfld, "Dune");
+        let did: i64 = conn
+            .query_row("SELECT did FROM cards", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(did, stable_id("Kindle"));
+    }
+
+    #[test]
+    fn stores_fields_as_html() {
+        let mut multi_line = dune(100, "x < y & z\nsecond line");
+        multi_line.comment = Some("a <b>".to_owned());
+        let conn = open_collection(package(&[multi_line], &AnkiConfig::default()));
+        let flds: String = conn
+            .query_row("SELECT flds FROM notes", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(
+            flds,
+            "Dune\x1fHerbert, Frank\x1fx &lt; y &amp; z<br>second line\x1fa &lt;b&gt;\x1f100-102\x1f"
+        );
+    }
+
+    #[test]
+    fn guid_survives_edits_of_the_highlight() {
+        let config = AnkiConfig::default();
+        let guid = |note: Note| -> String {
+            open_collection(package(&[note], &config))
+                .query_row("SELECT guid FROM notes", [], |row| row.get(0))
+                .unwrap()
+        };
+        let first = guid(dune(100, "Fear is the mind-killer."));
+        assert_eq!(first, guid(dune(100, "Fear is the mind-killer. Fear is")));
+        assert_ne!(first, guid(dune(200, "Fear is the mind-killer.")));
+    }
+
+    #[test]
+    fn highlights_sharing_a_start_keep_their_guid_in_any_package() {
+        let mut point = dune(100, "Fear");
+        point.location = Some(Location {
+            start: 100,
+            end: 100,
+        });
+        let mut notes = vec![point, dune(100, "The mind-killer")];
+        crate::my_clippings_parser::mark_shared_starts(&mut notes);
+        let guids = |notes: &[Note]| -> Vec<String> {
+            open_collection(package(notes, &AnkiConfig::default()))
+                .prepare("SELECT guid FROM notes ORDER BY id")
+                .unwrap()
+                .query_map([], |row| row.get(0))
+                .unwrap()
+                .collect::<rusqlite::Result<_>>()
+                .unwrap()
+        };
+        let both = guids(&notes);
+        assert_ne!(both[0], both[1]);
+        // A later package holding only the changed clipping updates its note
+        assert_eq!(guids(&notes[1..]), both[1..]);
+    }
+
+    #[test]
+    fn places_cards_in_templated_decks() {
+        let config = AnkiConfig {
+            deck_template: Some("{deck}::{title}".to_owned()),
+            ..AnkiConfig::default()
+        };
+        let mut hobbit = dune(10, "In a hole in the ground");
+        hobbit.title = "The Hobbit".to_owned();
+        let conn = open_collection(package(&[dune(100, "Fear"), hobbit], &config));
+        let dids: Vec<i64> = conn
+            .prepare("SELECT did FROM cards ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            dids,
+            [stable_id("Kindle::Dune"), stable_id("Kindle::The Hobbit")]
+        );
+    }
+
+    #[test]
+    fn checksum_is_first_sha1_word() {
+        // SHA-1 of "Dune" starts with c2b8e409
+        assert_eq!(checksum("Dune"), 0xc2b8_e409);
+    }
+}
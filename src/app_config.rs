@@ -1,6 +1,14 @@
+use crate::note::{Note, fill_placeholders};
 use anyhow::{Context, Result};
-use serde::Deserialize;
+use clap::ValueEnum;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Environment variable overriding `api_key`, to keep it out of config files
+pub const API_KEY_ENV: &str = "KINDLENOTES2ANKI_API_KEY";
 
 fn default_ignored() -> Vec<String> {
     vec![
@@ -10,13 +18,25 @@ fn default_ignored() -> Vec<String> {
     ]
 }
 
+fn default_page() -> Vec<String> {
+    vec!["page".to_owned()]
+}
+
+fn default_location() -> Vec<String> {
+    vec!["emplacement".to_owned(), "location".to_owned()]
+}
+
 #[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
 pub struct ParserConfig {
     pub bookmark: String,
     pub highlight: String,
     pub note: String,
-    #[serde(default = "default_ignored")]
     pub ignored: Vec<String>,
+    /// Words announcing the page number on the metadata line (case-insensitive)
+    pub page: Vec<String>,
+    /// Words announcing the location range on the metadata line (case-insensitive)
+    pub location: Vec<String>,
 }
 
 impl Default for ParserConfig {
@@ -26,37 +46,310 @@ impl Default for ParserConfig {
             highlight: "- Votre surlignement".to_owned(),
             note: "- Votre note".to_owned(),
             ignored: default_ignored(),
+            page: default_page(),
+            location: default_location(),
         }
     }
 }
 
+/// Where `AnkiConnect` looks for notes that already exist
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateScope {
+    #[default]
+    Deck,
+    Collection,
+}
+
+fn default_batch_size() -> NonZeroUsize {
+    NonZeroUsize::new(100).unwrap_or(NonZeroUsize::MIN)
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+fn default_retry_delay_ms() -> u64 {
+    500
+}
+
+fn default_front_field() -> String {
+    "Recto".to_owned()
+}
+
+fn default_back_field() -> String {
+    "Verso".to_owned()
+}
+
 #[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct AnkiConfig {
     pub deck: String,
+    /// Deck of each note, with `{deck}`, `{title}` and `{author}` placeholders,
+    /// e.g. `{deck}::{title}` for one subdeck per book. Defaults to `deck`.
+    #[serde(default)]
+    pub deck_template: Option<String>,
     pub model: String,
+    /// Anki field names mapped to templates over the clipping, see
+    /// [`crate::note::Note::render`]. Replaces the `*_field` keys when set.
+    #[serde(default)]
+    pub fields: IndexMap<String, String>,
+    #[serde(default = "default_front_field")]
     pub front_field: String,
+    #[serde(default = "default_back_field")]
     pub back_field: String,
+    /// Optional field receiving the book author
+    #[serde(default)]
+    pub author_field: Option<String>,
+    /// Optional field receiving the personal note attached to a highlight
+    #[serde(default)]
+    pub comment_field: Option<String>,
+    /// Optional field receiving the location of the clipping
+    #[serde(default)]
+    pub location_field: Option<String>,
+    /// Optional field receiving the date the clipping was added
+    #[serde(default)]
+    pub date_field: Option<String>,
     pub url: String,
+    /// Key required by `AnkiConnect` when its `apiKey` setting is set, see
+    /// also [`API_KEY_ENV`]
+    #[serde(default)]
+    pub api_key: Option<String>,
+    /// Number of notes sent to `AnkiConnect` per request
+    #[serde(default = "default_batch_size")]
+    pub batch_size: NonZeroUsize,
+    /// Number of times a request failing with a refused connection, a
+    /// timeout or a server error is sent again
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each following one
+    #[serde(default = "default_retry_delay_ms")]
+    pub retry_delay_ms: u64,
+    #[serde(default)]
+    pub duplicate_scope: DuplicateScope,
+    /// Tags of every note, with `{title_slug}`, `{author_slug}` and `{kind}`
+    /// placeholders. Also written as a last CSV column when not empty.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Create `model` as a bundled "Kindle Highlight" note type when missing
+    #[serde(default)]
+    pub create_model: bool,
 }
 
 impl Default for AnkiConfig {
     fn default() -> Self {
         Self {
             deck: "Kindle".to_owned(),
+            deck_template: None,
             model: "Basique".to_owned(),
-            front_field: "Recto".to_owned(),
-            back_field: "Verso".to_owned(),
+            fields: IndexMap::new(),
+            front_field: default_front_field(),
+            back_field: default_back_field(),
+            author_field: None,
+            comment_field: None,
+            location_field: None,
+            date_field: None,
             url: "http://localhost:8765".to_owned(),
+            api_key: None,
+            batch_size: default_batch_size(),
+            retries: default_retries(),
+            retry_delay_ms: default_retry_delay_ms(),
+            duplicate_scope: DuplicateScope::Deck,
+            tags: Vec::new(),
+            create_model: false,
+        }
+    }
+}
+
+/// Anki field filled by the import
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedField<'a> {
+    /// `*_field` config key naming the field, `None` for `[anki.fields]`
+    pub key: Option<&'static str>,
+    pub name: &'a str,
+    pub template: &'a str,
+}
+
+impl AnkiConfig {
+    /// Note type fields filled by the import, in order: the `[anki.fields]`
+    /// table, or else the `*_field` keys
+    pub fn mapped_fields(&self) -> Vec<MappedField<'_>> {
+        if !self.fields.is_empty() {
+            return self
+                .fields
+                .iter()
+                .map(|(name, template)| MappedField {
+                    key: None,
+                    name,
+                    template,
+                })
+                .collect();
+        }
+        let legacy = [
+            ("front_field", Some(&self.front_field), "{title}"),
+            ("back_field", Some(&self.back_field), "{text}"),
+            ("author_field", self.author_field.as_ref(), "{author}"),
+            ("comment_field", self.comment_field.as_ref(), "{comment}"),
+            ("location_field", self.location_field.as_ref(), "{location}"),
+            ("date_field", self.date_field.as_ref(), "{date}"),
+        ];
+        legacy
+            .into_iter()
+            .filter_map(|(key, name, template)| {
+                Some(MappedField {
+                    key: Some(key),
+                    name: name?,
+                    template,
+                })
+            })
+            .collect()
+    }
+
+    /// Deck receiving `note`: `deck_template` with its placeholders filled, or
+    /// the configured deck. Components left empty, e.g. by a missing author,
+    /// are dropped.
+    pub fn deck_name(&self, note: &Note) -> String {
+        let Some(template) = &self.deck_template else {
+            return self.deck.clone();
+        };
+        let name = fill_placeholders(template, |name| match name {
+            "deck" => Some(self.deck.clone()),
+            "title" => Some(sanitize_deck_component(&note.title)),
+            "author" => Some(sanitize_deck_component(
+                note.author.as_deref().unwrap_or_default(),
+            )),
+            _ => None,
+        });
+        let components: Vec<&str> = name
+            .split("::")
+            .map(str::trim)
+            .filter(|component| !component.is_empty())
+            .collect();
+        if components.is_empty() {
+            self.deck.clone()
+        } else {
+            components.join("::")
         }
     }
 }
 
+/// Keeps a book title or author a single deck name component: `::` would
+/// nest decks, and Anki strips double quotes and collapses whitespace
+fn sanitize_deck_component(text: &str) -> String {
+    let mut sanitized = String::with_capacity(text.len());
+    for word in text.split_whitespace() {
+        if !sanitized.is_empty() {
+            sanitized.push(' ');
+        }
+        sanitized.push_str(word);
+    }
+    while sanitized.contains("::") {
+        sanitized = sanitized.replace("::", ":");
+    }
+    sanitized.retain(|c| c != '"' && !c.is_control());
+    sanitized
+}
+
+/// Book selection: patterns are case-insensitive substrings, or regular
+/// expressions when wrapped in slashes (`/^The /`)
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct FilterConfig {
+    /// Only keep books whose title matches one of these patterns
+    #[serde(default)]
+    pub books: Vec<String>,
+    /// Drop books whose title matches one of these patterns
+    #[serde(default)]
+    pub exclude_books: Vec<String>,
+    /// Only keep books whose author matches one of these patterns
+    #[serde(default)]
+    pub authors: Vec<String>,
+}
+
+/// Column of the CSV output
+#[derive(Deserialize, ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CsvColumn {
+    Title,
+    Author,
+    Text,
+    Comment,
+    Page,
+    Location,
+    Date,
+    Kind,
+    Tags,
+}
+
+/// Field delimiter of the CSV output: a single printable ASCII character
+/// other than the `"` quote, or `tab`
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct Delimiter(pub u8);
+
+impl Default for Delimiter {
+    fn default() -> Self {
+        Self(b',')
+    }
+}
+
+impl FromStr for Delimiter {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "tab" | "\\t" => Ok(Self(b'\t')),
+            _ => match value.as_bytes() {
+                // The quote and line breaks would make the rows unreadable
+                &[byte] if byte == b'\t' || byte == b' ' || byte.is_ascii_graphic() => {
+                    if byte == b'"' {
+                        Err("invalid delimiter `\"`, which quotes CSV fields".to_owned())
+                    } else {
+                        Ok(Self(byte))
+                    }
+                }
+                _ => Err(format!(
+                    "invalid delimiter `{}`, expected a single printable ASCII character or `tab`",
+                    value.escape_default()
+                )),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for Delimiter {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct CsvConfig {
+    /// Columns, in order. Defaults to the `[anki]` fields, followed by the
+    /// tags when tag templates are configured.
+    #[serde(default)]
+    pub columns: Vec<CsvColumn>,
+    #[serde(default)]
+    pub delimiter: Delimiter,
+    /// Start with a row naming the columns
+    #[serde(default)]
+    pub header: bool,
+    /// Start with the header lines of Anki 2.1.55+ selecting the note type,
+    /// deck and columns
+    #[serde(default)]
+    pub anki_headers: bool,
+}
+
 #[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
 pub struct AppConfig {
     #[serde(default)]
     pub parser: ParserConfig,
     #[serde(default)]
     pub anki: AnkiConfig,
+    #[serde(default)]
+    pub filter: FilterConfig,
+    #[serde(default)]
+    pub csv: CsvConfig,
 }
 
 impl AppConfig {
@@ -91,7 +384,16 @@ mod tests {
         assert_eq!(config.anki.model, "Basique");
         assert_eq!(config.anki.front_field, "Recto");
         assert_eq!(config.anki.back_field, "Verso");
+        assert_eq!(config.anki.author_field, None);
+        assert_eq!(config.anki.comment_field, None);
         assert_eq!(config.anki.url, "http://localhost:8765");
+        assert_eq!(config.anki.duplicate_scope, DuplicateScope::Deck);
+        assert!(!config.anki.create_model);
+        assert_eq!(config.anki.batch_size.get(), 100);
+        assert_eq!(config.anki.retries, 3);
+        assert_eq!(config.anki.api_key, None);
+        assert_eq!(config.anki.retry_delay_ms, 500);
+        assert_eq!(config.filter, FilterConfig::default());
         assert_eq!(
             config.parser.ignored,
             [
@@ -99,6 +401,8 @@ mod tests {
                 "<You have reached the clipping limit"
             ]
         );
+        assert_eq!(config.parser.page, ["page"]);
+        assert_eq!(config.parser.location, ["emplacement", "location"]);
     }
 
     #[test]
@@ -147,7 +451,9 @@ deck = "Clippings"
 model = "Basic"
 front_field = "Front"
 back_field = "Back"
+author_field = "Author"
 url = "http://127.0.0.1:9999"
+duplicate_scope = "collection"
 "#
         )
         .unwrap();
@@ -155,7 +461,9 @@ url = "http://127.0.0.1:9999"
         let config = AppConfig::new(Some(file.path().to_path_buf())).unwrap();
         assert_eq!(config.parser, ParserConfig::default());
         assert_eq!(config.anki.deck, "Clippings");
+        assert_eq!(config.anki.author_field.as_deref(), Some("Author"));
         assert_eq!(config.anki.url, "http://127.0.0.1:9999");
+        assert_eq!(config.anki.duplicate_scope, DuplicateScope::Collection);
     }
 
     #[test]
@@ -177,6 +485,198 @@ ignored = ["<skip me>"]
         assert_eq!(config.parser.ignored, ["<skip me>"]);
     }
 
+    #[test]
+    fn test_kindle_highlight_configuration() {
+        let mut file = NamedTempFile::new().unwrap();
+        write!(
+            file,
+            "{}",
+            include_str!("resources/kindle_highlight_config.toml")
+        )
+        .unwrap();
+        let config = AppConfig::new(Some(file.path().to_path_buf())).unwrap();
+
+        assert_eq!(config.anki.model, "Kindle Highlight");
+        assert!(config.anki.create_model);
+        assert_eq!(config.anki.front_field, "Title");
+        assert_eq!(config.anki.back_field, "Highlight");
+        assert_eq!(config.anki.author_field.as_deref(), Some("Author"));
+        assert_eq!(config.anki.comment_field.as_deref(), Some("Note"));
+        let names: Vec<_> = config
+            .anki
+            .mapped_fields()
+            .iter()
+            .map(|field| (field.key, field.name, field.template))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                (Some("front_field"), "Title", "{title}"),
+                (Some("back_field"), "Highlight", "{text}"),
+                (Some("author_field"), "Author", "{author}"),
+                (Some("comment_field"), "Note", "{comment}"),
+                (Some("location_field"), "Location", "{location}"),
+                (Some("date_field"), "Date", "{date}"),
+            ]
+        );
+        assert_eq!(config.anki.location_field.as_deref(), Some("Location"));
+        assert_eq!(config.anki.date_field.as_deref(), Some("Date"));
+        assert_eq!(
+            config.anki.tags,
+            [
+                "kindle",
+                "book::{title_slug}",
+                "author::{author_slug}",
+                "{kind}"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_deck_template_fills_placeholders() {
+        let config = AnkiConfig {
+            deck_template: Some("{deck}::{author}::{title}".to_owned()),
+            ..AnkiConfig::default()
+        };
+        let mut note = Note {
+            title: "Book A".to_owned(),
+            author: Some("Author, A".to_owned()),
+            ..Note::default()
+        };
+        assert_eq!(config.deck_name(&note), "Kindle::Author, A::Book A");
+        assert_eq!(AnkiConfig::default().deck_name(&note), "Kindle");
+
+        note.title = "  C++::The \"Good\"   Parts ".to_owned();
+        assert_eq!(
+            config.deck_name(&note),
+            "Kindle::Author, A::C++:The Good Parts"
+        );
+        note.author = None;
+        assert_eq!(config.deck_name(&note), "Kindle::C++:The Good Parts");
+
+        // Placeholders in the title are not expanded again
+        note.title = "Using {author} and {deck}".to_owned();
+        note.author = Some("Author, A".to_owned());
+        assert_eq!(
+            config.deck_name(&note),
+            "Kindle::Author, A::Using {author} and {deck}"
+        );
+    }
+
+    #[test]
+    fn test_field_templates_keep_their_order() {
+        let mut file = NamedTempFile::new().unwrap();
+        write!(
+            file,
+            r#"
+[anki]
+deck = "Kindle"
+model = "Quote"
+url = "http://localhost:8765"
+
+[anki.fields]
+Source = "{{title}}"
+Quote = "{{text}}"
+Comment = "{{comment}}"
+Author = "{{author}}"
+"#
+        )
+        .unwrap();
+        let config = AppConfig::new(Some(file.path().to_path_buf())).unwrap();
+
+        let fields: Vec<_> = config
+            .anki
+            .mapped_fields()
+            .iter()
+            .map(|field| (field.key, field.name, field.template))
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                (None, "Source", "{title}"),
+                (None, "Quote", "{text}"),
+                (None, "Comment", "{comment}"),
+                (None, "Author", "{author}"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_filter_section() {
+        let mut file = NamedTempFile::new().unwrap();
+        write!(
+            file,
+            r#"
+[filter]
+books = ["Dune", "/^The /"]
+exclude_books = ["Le Monde"]
+"#
+        )
+        .unwrap();
+
+        let config = AppConfig::new(Some(file.path().to_path_buf())).unwrap();
+        assert_eq!(config.filter.books, ["Dune", "/^The /"]);
+        assert_eq!(config.filter.exclude_books, ["Le Monde"]);
+        assert!(config.filter.authors.is_empty());
+        assert_eq!(config.parser, ParserConfig::default());
+    }
+
+    #[test]
+    fn test_zero_batch_size_is_rejected() {
+        let mut file = NamedTempFile::new().unwrap();
+        write!(
+            file,
+            r#"
+[anki]
+deck = "Kindle"
+model = "Basic"
+url = "http://localhost:8765"
+batch_size = 0
+"#
+        )
+        .unwrap();
+        let err = AppConfig::new(Some(file.path().to_path_buf())).unwrap_err();
+        assert!(format!("{err:#}").contains("batch_size"), "{err:#}");
+    }
+
+    #[test]
+    fn test_csv_section() {
+        let mut file = NamedTempFile::new().unwrap();
+        write!(
+            file,
+            r#"
+[csv]
+columns = ["title", "text", "tags"]
+delimiter = "tab"
+header = true
+"#
+        )
+        .unwrap();
+
+        let config = AppConfig::new(Some(file.path().to_path_buf())).unwrap();
+        assert_eq!(
+            config.csv.columns,
+            [CsvColumn::Title, CsvColumn::Text, CsvColumn::Tags]
+        );
+        assert_eq!(config.csv.delimiter, Delimiter(b'\t'));
+        assert!(config.csv.header);
+        assert!(!config.csv.anki_headers);
+    }
+
+    #[test]
+    fn test_delimiter_parsing() {
+        assert_eq!(";".parse(), Ok(Delimiter(b';')));
+        assert_eq!("\\t".parse(), Ok(Delimiter(b'\t')));
+        assert!("".parse::<Delimiter>().is_err());
+        assert!(";;".parse::<Delimiter>().is_err());
+        assert!("é".parse::<Delimiter>().is_err());
+        assert_eq!("\t".parse(), Ok(Delimiter(b'\t')));
+        assert_eq!(" ".parse(), Ok(Delimiter(b' ')));
+        for invalid in ["\"", "\n", "\r", "\0", "\x1f", "\x7f"] {
+            assert!(invalid.parse::<Delimiter>().is_err(), "{invalid:?}");
+        }
+    }
+
     #[test]
     fn test_missing_config_file() {
         let err = AppConfig::new(Some(PathBuf::from("does-not-exist.toml"))).unwrap_err();
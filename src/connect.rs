@@ -1,25 +1,65 @@
-use crate::app_config::AnkiConfig;
-use crate::note::Note as AppNote;
+use crate::app_config::{AnkiConfig, DuplicateScope};
+use crate::note::{Note as AppNote, to_html};
 use anyhow::{Context, Result, bail};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
-use std::time::Duration;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, ErrorKind, Write};
+use std::thread;
+use std::time::{Duration, Instant};
 
-const DUPLICATE_SCOPE: &str = "deck";
+const API_VERSION: usize = 6;
 const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Interval between two `version` requests of `wait_for_anki`
+const WAIT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Actions that Anki may have applied when a request timed out or failed
+/// midway, and which would then create duplicates when sent again
+const NON_IDEMPOTENT_ACTIONS: [&str; 2] = ["addNotes", "createModel"];
+/// Prefix of the tag holding the stable id of a clipping
+const ID_TAG_PREFIX: &str = "kindle-id::";
+
+/// Name of the bundled note type, and of its card template
+pub const MODEL_NAME: &str = "Kindle Highlight";
+/// Fields of the bundled note type
+pub const MODEL_FIELDS: [&str; 6] = ["Title", "Author", "Highlight", "Note", "Location", "Date"];
+pub const MODEL_FRONT: &str = r#"<div class="title">{{Title}}</div>
+{{#Author}}<div class="author">{{Author}}</div>{{/Author}}
+{{#Note}}<div class="note">{{Note}}</div>{{/Note}}"#;
+pub const MODEL_BACK: &str = r#"{{FrontSide}}
+<hr id="answer">
+<blockquote class="highlight">{{Highlight}}</blockquote>
+<div class="meta">{{#Location}}Location {{Location}}{{/Location}} {{Date}}</div>"#;
+pub const MODEL_CSS: &str = ".card {
+  font-family: Georgia, serif;
+  font-size: 20px;
+  text-align: left;
+  color: black;
+  background-color: white;
+}
+.title { font-weight: bold; }
+.author { font-style: italic; color: #555; }
+.note { margin-top: 1em; }
+.highlight {
+  white-space: pre-wrap;
+  border-left: 4px solid #f5c518;
+  margin: 1em 0;
+  padding-left: 1em;
+}
+.meta { font-size: 14px; color: #888; }
+.nightMode .author, .nightMode .meta { color: #aaa; }";
 
 #[derive(Deserialize)]
-struct ApiResponse {
-    result: Option<Vec<Option<usize>>>,
+struct ApiResponse<T> {
+    result: Option<T>,
     error: Option<String>,
 }
 
 #[derive(Serialize, Debug, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
-struct AddNotes<'a> {
+struct Request<'a, P> {
     action: &'a str,
     version: usize,
-    params: Notes<'a>,
+    params: P,
 }
 
 #[derive(Serialize, Debug, PartialEq, Eq)]
@@ -31,87 +71,669 @@ struct Notes<'a> {
 #[derive(Serialize, Debug, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 struct Note<'a> {
-    deck_name: &'a str,
+    deck_name: String,
     model_name: &'a str,
-    fields: BTreeMap<&'a str, &'a str>,
-    options: Options<'a>,
+    fields: BTreeMap<&'a str, String>,
+    tags: Vec<String>,
+    options: Options,
 }
 
 #[derive(Serialize, Debug, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
-struct Options<'a> {
+struct Options {
     allow_duplicate: bool,
-    duplicate_scope: &'a str,
+    duplicate_scope: DuplicateScope,
+}
+
+/// Request carrying the API key, when `AnkiConnect` requires one
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct Keyed<'a, R> {
+    #[serde(flatten)]
+    request: R,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<&'a str>,
 }
 
-pub fn add_notes(notes: &[AppNote], config: &AnkiConfig) -> Result<usize> {
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct Actions<'a, P> {
+    actions: Vec<Keyed<'a, Request<'a, P>>>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct Query {
+    query: String,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct NoParams {}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct NoteIds {
+    notes: Vec<u64>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct NoteUpdate<'a> {
+    note: UpdatedFields<'a>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct UpdatedFields<'a> {
+    id: u64,
+    fields: BTreeMap<&'a str, String>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct AddTags {
+    notes: [u64; 1],
+    tags: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NoteInfo {
+    note_id: u64,
+    fields: BTreeMap<String, FieldValue>,
+    tags: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct FieldValue {
+    value: String,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct Deck<'a> {
+    deck: &'a str,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+struct ModelName<'a> {
+    model_name: &'a str,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+struct CreateModel<'a> {
+    model_name: &'a str,
+    in_order_fields: &'a [&'a str],
+    css: &'a str,
+    is_cloze: bool,
+    card_templates: [CardTemplate<'a>; 1],
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "PascalCase")]
+struct CardTemplate<'a> {
+    name: &'a str,
+    front: &'a str,
+    back: &'a str,
+}
+
+/// Outcome of an import
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    /// Notes created by this import
+    pub created: usize,
+    /// Notes already in Anki whose fields were updated
+    pub updated: usize,
+    /// Notes already in Anki with the same fields
+    pub unchanged: usize,
+}
+
+/// Imports `notes`, updating the fields of the ones already in Anki
+pub fn add_notes(notes: &[AppNote], config: &AnkiConfig) -> Result<ImportSummary> {
     if notes.is_empty() {
-        return Ok(0);
+        return Ok(ImportSummary::default());
+    }
+    let agent = new_agent();
+    let decks: BTreeSet<String> = notes.iter().map(|note| config.deck_name(note)).collect();
+    ensure_decks_and_model(&agent, &decks, config)?;
+    let existing = find_existing_notes(&agent, notes, config)?;
+    let mut new_notes = Vec::new();
+    let mut found = Vec::new();
+    for (note, id) in notes.iter().zip(existing) {
+        match id {
+            Some(id) => found.push((id, note)),
+            None => new_notes.push(note),
+        }
+    }
+    let mut summary = update_existing_notes(&agent, &found, config)?;
+    if new_notes.is_empty() {
+        return Ok(summary);
+    }
+
+    summary.created = create_notes(&agent, &new_notes, config)?;
+    Ok(summary)
+}
+
+/// Sends `addNotes` requests of `batch_size` notes, so that Anki never
+/// stalls long enough to time out, stopping at the first failed batch
+fn create_notes(agent: &ureq::Agent, notes: &[&AppNote], config: &AnkiConfig) -> Result<usize> {
+    let batch_size = config.batch_size.get();
+    let total = notes.len();
+    let batches = total.div_ceil(batch_size);
+    let mut created = 0;
+    for (index, batch) in notes.chunks(batch_size).enumerate() {
+        let first = index * batch_size + 1;
+        let last = first + batch.len() - 1;
+        let label = format!(
+            "batch {}/{batches} (new notes {first}-{last} of {total})",
+            index + 1
+        );
+        let req = build_add_notes_request(batch.to_vec(), config);
+        let result: Vec<Option<u64>> = invoke(agent, config, &req).with_context(|| {
+            format!(
+                "Failed to send {label}: {}, the notes of this batch may or may not have been created{}",
+                created_so_far(first),
+                not_sent_after(last, total)
+            )
+        })?;
+        let failed: Vec<String> = batch
+            .iter()
+            .zip(result.iter().chain(std::iter::repeat(&None)))
+            .filter(|(_, id)| id.is_none())
+            .map(|(note, _)| describe(note))
+            .collect();
+        created += batch.len() - failed.len();
+        if !failed.is_empty() {
+            bail!(
+                "Some notes could not be created in {label} ({}/{} succeeded): {}, not created: {}{}",
+                batch.len() - failed.len(),
+                batch.len(),
+                created_so_far(first),
+                failed.join(", "),
+                not_sent_after(last, total)
+            );
+        }
+        if batches > 1 {
+            writeln!(
+                io::stderr(),
+                "Sent batch {}/{batches}: {created}/{total} new notes created",
+                index + 1
+            )?;
+        }
+    }
+    Ok(created)
+}
+
+fn created_so_far(first_of_batch: usize) -> String {
+    match first_of_batch - 1 {
+        0 => "no note created before".to_owned(),
+        before => format!("new notes 1-{before} created"),
+    }
+}
+
+fn not_sent_after(last_of_batch: usize, total: usize) -> String {
+    if last_of_batch < total {
+        format!(
+            "; new notes {}-{total} not sent, run the import again to send them",
+            last_of_batch + 1
+        )
+    } else {
+        String::new()
+    }
+}
+
+/// Short description of a note for error messages
+fn describe(note: &AppNote) -> String {
+    const EXCERPT_LENGTH: usize = 40;
+    let mut excerpt: String = note.tidied_note.chars().take(EXCERPT_LENGTH).collect();
+    if note.tidied_note.chars().count() > EXCERPT_LENGTH {
+        excerpt.push('…');
     }
-    let notes_count = notes.len();
-    let req = build_add_notes_request(notes, config);
+    format!("{} \"{excerpt}\"", note.title)
+}
+
+/// Updates the fields of the notes found in Anki when they differ, and
+/// tags the ones imported without a stable id so that later edits of the
+/// highlight are found too
+fn update_existing_notes(
+    agent: &ureq::Agent,
+    found: &[(u64, &AppNote)],
+    config: &AnkiConfig,
+) -> Result<ImportSummary> {
+    let mut summary = ImportSummary::default();
+    if found.is_empty() {
+        return Ok(summary);
+    }
+    let mut infos: Vec<NoteInfo> = Vec::with_capacity(found.len());
+    for batch in found.chunks(config.batch_size.get()) {
+        let ids = batch.iter().map(|&(id, _)| id).collect();
+        let batch_infos: Vec<NoteInfo> =
+            invoke(agent, config, &request("notesInfo", NoteIds { notes: ids }))?;
+        infos.extend(batch_infos);
+    }
+    let mut updates = Vec::new();
+    let mut id_tags = Vec::new();
+    for &(id, note) in found {
+        let Some(info) = infos.iter().find(|info| info.note_id == id) else {
+            bail!("Unexpected AnkiConnect response: no info for note {id}");
+        };
+        let fields = note_fields(note, config);
+        let changed = fields.iter().any(|(name, value)| {
+            info.fields
+                .get(*name)
+                .is_none_or(|field| field.value != *value)
+        });
+        if changed {
+            summary.updated += 1;
+            updates.push(request(
+                "updateNoteFields",
+                NoteUpdate {
+                    note: UpdatedFields { id, fields },
+                },
+            ));
+        } else {
+            summary.unchanged += 1;
+        }
+        if let Some(tag) = id_tag(note).filter(|tag| !info.tags.contains(tag)) {
+            id_tags.push(request(
+                "addTags",
+                AddTags {
+                    notes: [id],
+                    tags: tag,
+                },
+            ));
+        }
+    }
+    for response in invoke_multi::<_, serde::de::IgnoredAny>(agent, config, updates)? {
+        check_error(&response).context("Failed to update note fields")?;
+    }
+    for response in invoke_multi::<_, serde::de::IgnoredAny>(agent, config, id_tags)? {
+        check_error(&response).context("Failed to tag notes")?;
+    }
+    Ok(summary)
+}
+
+/// Polls the `version` action until `AnkiConnect` answers, for imports
+/// started along with Anki. Only connection failures are polled again: an
+/// error returned by `AnkiConnect`, like a wrong API key, fails right away.
+pub fn wait_for_anki(config: &AnkiConfig, timeout: Duration) -> Result<()> {
+    let agent = new_agent();
+    // Polling replaces the retries
+    let config = AnkiConfig {
+        retries: 0,
+        ..config.clone()
+    };
+    let deadline = Instant::now() + timeout;
+    let mut announced = false;
+    loop {
+        let Err(error) = invoke::<_, u32>(&agent, &config, &request("version", NoParams {})) else {
+            return Ok(());
+        };
+        if !error
+            .downcast_ref::<ureq::Error>()
+            .is_some_and(|error| is_transient(error, "version"))
+        {
+            return Err(error);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(error).with_context(|| {
+                format!(
+                    "AnkiConnect at {} did not answer within {} seconds",
+                    config.url,
+                    timeout.as_secs()
+                )
+            });
+        }
+        if !announced {
+            writeln!(io::stderr(), "Waiting for AnkiConnect at {}...", config.url)?;
+            announced = true;
+        }
+        thread::sleep(WAIT_POLL_INTERVAL.min(deadline - now));
+    }
+}
+
+fn new_agent() -> ureq::Agent {
     let agent_config = ureq::Agent::config_builder()
         .timeout_global(Some(REQUEST_TIMEOUT))
         .build();
-    let agent = ureq::Agent::new_with_config(agent_config);
-    let mut response = match agent.post(&config.url).send_json(&req) {
+    ureq::Agent::new_with_config(agent_config)
+}
+
+/// Sends one `AnkiConnect` request and unwraps its result, retrying
+/// transient failures with exponential backoff
+fn invoke<P: Serialize, T: DeserializeOwned>(
+    agent: &ureq::Agent,
+    config: &AnkiConfig,
+    request: &Request<P>,
+) -> Result<T> {
+    let url = &config.url;
+    let mut attempt = 0;
+    let result = loop {
+        let result = agent.post(url).send_json(Keyed {
+            request,
+            key: config.api_key.as_deref(),
+        });
+        match &result {
+            Err(error) if attempt < config.retries && is_transient(error, request.action) => {
+                let delay = Duration::from_millis(config.retry_delay_ms)
+                    .saturating_mul(2_u32.saturating_pow(attempt));
+                writeln!(
+                    io::stderr(),
+                    "AnkiConnect request failed ({error}), retrying in {} ms",
+                    delay.as_millis()
+                )?;
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            _ => break result,
+        }
+    };
+    let mut response = match result {
         Ok(response) => response,
-        Err(ureq::Error::StatusCode(code)) => bail!(
-            "AnkiConnect at {} returned HTTP {code}. Check that Anki is idle (not syncing) and retry.",
-            config.url
-        ),
+        Err(err @ ureq::Error::StatusCode(code)) => {
+            return Err(err).with_context(|| {
+                format!(
+                    "AnkiConnect at {url} returned HTTP {code}. Check that Anki is idle (not syncing) and retry."
+                )
+            });
+        }
         Err(err) => {
             return Err(err).with_context(|| {
                 format!(
-                    "Failed to connect to AnkiConnect at {}. Is Anki running with the AnkiConnect add-on?",
-                    config.url
+                    "Failed to connect to AnkiConnect at {url}. Is Anki running with the AnkiConnect add-on?"
                 )
             });
         }
     };
-    let parsed: ApiResponse = response
+    let parsed: ApiResponse<T> = response
         .body_mut()
         .read_json()
         .context("Failed to parse AnkiConnect response")?;
+    unwrap_response(parsed)
+}
+
+/// Tells whether `error` may go away by sending `action` again: a refused
+/// connection always, timeouts and server errors for idempotent actions
+fn is_transient(error: &ureq::Error, action: &str) -> bool {
+    match error {
+        ureq::Error::ConnectionFailed => true,
+        ureq::Error::Io(err) if err.kind() == ErrorKind::ConnectionRefused => true,
+        _ if NON_IDEMPOTENT_ACTIONS.contains(&action) => false,
+        ureq::Error::Timeout(_) | ureq::Error::Io(_) => true,
+        ureq::Error::StatusCode(code) => *code >= 500,
+        _ => false,
+    }
+}
+
+/// Sends `actions` in `multi` requests of `batch_size` actions, returning
+/// one response per action. No request is made when there is no action.
+fn invoke_multi<P: Serialize, T: DeserializeOwned>(
+    agent: &ureq::Agent,
+    config: &AnkiConfig,
+    actions: Vec<Request<P>>,
+) -> Result<Vec<ApiResponse<T>>> {
+    let mut responses = Vec::with_capacity(actions.len());
+    let mut actions = actions.into_iter().peekable();
+    while actions.peek().is_some() {
+        // `AnkiConnect` checks the key of each action of a `multi` request
+        let batch: Vec<_> = actions
+            .by_ref()
+            .take(config.batch_size.get())
+            .map(|request| Keyed {
+                request,
+                key: config.api_key.as_deref(),
+            })
+            .collect();
+        let count = batch.len();
+        let batch_responses: Vec<ApiResponse<T>> =
+            invoke(agent, config, &request("multi", Actions { actions: batch }))?;
+        if batch_responses.len() != count {
+            bail!(
+                "Unexpected AnkiConnect response: {} results for {count} actions",
+                batch_responses.len()
+            );
+        }
+        responses.extend(batch_responses);
+    }
+    Ok(responses)
+}
+
+fn request<P>(action: &str, params: P) -> Request<'_, P> {
+    Request {
+        action,
+        version: API_VERSION,
+        params,
+    }
+}
 
-    if let Some(error) = parsed.error {
+/// Creates the note type when `create_model` is set and the target decks,
+/// so that importing into a fresh profile does not fail. The configured
+/// fields are checked first, so that a typo changes nothing in Anki.
+fn ensure_decks_and_model(
+    agent: &ureq::Agent,
+    decks: &BTreeSet<String>,
+    config: &AnkiConfig,
+) -> Result<()> {
+    let models: Vec<String> = invoke(agent, config, &request("modelNames", NoParams {}))?;
+    let model_exists = models.contains(&config.model);
+    if !model_exists && !config.create_model {
+        bail!(
+            "Note type {} does not exist in Anki. Create it, or set `create_model = true` in the [anki] config to create a Kindle Highlight note type.",
+            config.model
+        );
+    }
+    let model_fields: Vec<String> = if model_exists {
+        invoke(
+            agent,
+            config,
+            &request(
+                "modelFieldNames",
+                ModelName {
+                    model_name: &config.model,
+                },
+            ),
+        )
+        .with_context(|| format!("Failed to read the fields of note type {}", config.model))?
+    } else {
+        MODEL_FIELDS.iter().map(|&field| field.to_owned()).collect()
+    };
+    check_field_names(&model_fields, config)?;
+
+    if !model_exists {
+        let _: serde::de::IgnoredAny = invoke(
+            agent,
+            config,
+            &request("createModel", build_create_model_params(&config.model)),
+        )
+        .with_context(|| format!("Failed to create note type {}", config.model))?;
+        writeln!(io::stderr(), "Created note type {}", config.model)?;
+    }
+    let existing: Vec<String> = invoke(agent, config, &request("deckNames", NoParams {}))?;
+    for deck in decks.iter().filter(|deck| !existing.contains(deck)) {
+        let _: u64 = invoke(agent, config, &request("createDeck", Deck { deck }))
+            .with_context(|| format!("Failed to create deck {deck}"))?;
+        writeln!(io::stderr(), "Created deck {deck}")?;
+    }
+    Ok(())
+}
+
+/// Fails early on configured fields the note type does not have, which
+/// `AnkiConnect` would otherwise reject with an opaque error
+fn check_field_names(model_fields: &[String], config: &AnkiConfig) -> Result<()> {
+    let unknown: Vec<String> = config
+        .mapped_fields()
+        .into_iter()
+        .filter(|field| !model_fields.iter().any(|known| known == field.name))
+        .map(|field| match field.key {
+            Some(key) => format!("{key} = \"{}\"", field.name),
+            None => format!("\"{}\"", field.name),
+        })
+        .collect();
+    if !unknown.is_empty() {
+        bail!(
+            "Note type {} has no field named as configured in [anki]: {}. Valid field names: {}",
+            config.model,
+            unknown.join(", "),
+            model_fields.join(", ")
+        );
+    }
+    Ok(())
+}
+
+fn build_create_model_params(model_name: &str) -> CreateModel<'_> {
+    CreateModel {
+        model_name,
+        in_order_fields: &MODEL_FIELDS,
+        css: MODEL_CSS,
+        is_cloze: false,
+        card_templates: [CardTemplate {
+            name: MODEL_NAME,
+            front: MODEL_FRONT,
+            back: MODEL_BACK,
+        }],
+    }
+}
+
+/// Fails on an `AnkiConnect` error, for actions whose result is `null`
+fn check_error<T>(response: &ApiResponse<T>) -> Result<()> {
+    if let Some(error) = &response.error {
         bail!("AnkiConnect error: {error}");
     }
-    let Some(result) = parsed.result else {
+    Ok(())
+}
+
+fn unwrap_response<T>(response: ApiResponse<T>) -> Result<T> {
+    check_error(&response)?;
+    let Some(result) = response.result else {
         bail!("Unexpected AnkiConnect response: missing result");
     };
-    let created = result.into_iter().flatten().count();
-    if created == notes_count {
-        Ok(created)
+    Ok(result)
+}
+
+/// Finds the note already in Anki for each clipping, by stable id or with
+/// the same highlight text. Anki's own duplicate check
+/// (`canAddNotes`, `allowDuplicate`) only compares the first field, which is
+/// the book title shared by every highlight of a book, so each note is
+/// looked up with `findNotes` instead, all in a single `multi` request.
+fn find_existing_notes(
+    agent: &ureq::Agent,
+    notes: &[AppNote],
+    config: &AnkiConfig,
+) -> Result<Vec<Option<u64>>> {
+    let queries: Vec<Option<String>> = notes
+        .iter()
+        .map(|note| duplicate_query(note, config))
+        .collect();
+    let actions = queries
+        .iter()
+        .flatten()
+        .map(|query| {
+            request(
+                "findNotes",
+                Query {
+                    query: query.clone(),
+                },
+            )
+        })
+        .collect();
+    let mut found =
+        invoke_multi(agent, config, actions)?
+            .into_iter()
+            .map(|result: ApiResponse<Vec<u64>>| {
+                unwrap_response(result).map(|ids| ids.first().copied())
+            });
+    // Notes without a query cannot be found and are created
+    queries
+        .iter()
+        .map(|query| match query {
+            Some(_) => found.next().unwrap_or(Ok(None)),
+            None => Ok(None),
+        })
+        .collect()
+}
+
+/// Anki search matching the note tagged with the stable id of the
+/// clipping, or with the same highlight in the field rendering `{text}`,
+/// converted to HTML as stored by Anki.
+/// Other fields, such as the book title, are shared by many highlights and
+/// do not identify a note. `None` when the note can be matched on neither.
+fn duplicate_query(note: &AppNote, config: &AnkiConfig) -> Option<String> {
+    let quote = |term: String| format!("\"{}\"", escape_search(&term));
+    let content = config
+        .mapped_fields()
+        .into_iter()
+        .find(|field| field.template.contains("{text}"))
+        .map(|field| {
+            quote(format!(
+                "{}:{}",
+                field.name,
+                to_html(&note.render(field.template))
+            ))
+        });
+    let id = id_tag(note).map(|tag| quote(format!("tag:{tag}")));
+    let matching = match (id, content) {
+        (Some(id), Some(content)) => format!("({id} OR {content})"),
+        (Some(term), None) | (None, Some(term)) => term,
+        (None, None) => return None,
+    };
+    Some(if config.duplicate_scope == DuplicateScope::Deck {
+        format!(
+            "{} {matching}",
+            quote(format!("deck:{}", config.deck_name(note)))
+        )
     } else {
-        bail!("Some notes could not be created ({created}/{notes_count} succeeded)");
+        matching
+    })
+}
+
+/// Tag holding the stable id of the clipping, see [`AppNote::stable_id`]
+fn id_tag(note: &AppNote) -> Option<String> {
+    note.stable_id().map(|id| format!("{ID_TAG_PREFIX}{id}"))
+}
+
+/// Escapes the characters Anki search treats specially inside quotes
+fn escape_search(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '"' | '*' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
     }
+    escaped
 }
 
-fn build_add_notes_request<'a>(notes: &'a [AppNote], config: &'a AnkiConfig) -> AddNotes<'a> {
-    AddNotes {
-        action: "addNotes",
-        version: 6,
-        params: Notes {
+fn build_add_notes_request<'a>(
+    notes: Vec<&'a AppNote>,
+    config: &'a AnkiConfig,
+) -> Request<'a, Notes<'a>> {
+    request(
+        "addNotes",
+        Notes {
             notes: notes
-                .iter()
+                .into_iter()
                 .map(|note| fill_note_api_params(note, config))
                 .collect(),
         },
-    }
+    )
+}
+
+fn note_fields<'a>(note: &AppNote, config: &'a AnkiConfig) -> BTreeMap<&'a str, String> {
+    config
+        .mapped_fields()
+        .into_iter()
+        .map(|field| (field.name, to_html(&note.render(field.template))))
+        .collect()
 }
 
 fn fill_note_api_params<'a>(note: &'a AppNote, config: &'a AnkiConfig) -> Note<'a> {
-    let mut fields = BTreeMap::new();
-    fields.insert(config.front_field.as_str(), note.title.as_str());
-    fields.insert(config.back_field.as_str(), note.tidied_note.as_str());
+    let mut tags = note.tags(&config.tags);
+    tags.extend(id_tag(note));
     Note {
-        deck_name: &config.deck,
+        deck_name: config.deck_name(note),
         model_name: &config.model,
-        fields,
+        fields: note_fields(note, config),
+        tags,
+        // Duplicates were filtered out beforehand on the id and text, while
+        // Anki would reject every highlight sharing the book title
         options: Options {
             allow_duplicate: true,
-            duplicate_scope: DUPLICATE_SCOPE,
+            duplicate_scope: config.duplicate_scope,
         },
     }
 }
@@ -120,8 +742,10 @@ fn fill_note_api_params<'a>(note: &'a AppNote, config: &'a AnkiConfig) -> Note<'
 mod tests {
     use super::*;
     use crate::note::Note as AppNote;
+    use std::collections::HashMap;
     use std::io::{Read, Write};
     use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
     use std::thread;
     use std::time::Duration;
 
@@ -129,11 +753,15 @@ mod tests {
         vec![
             AppNote {
                 title: "Book A".to_owned(),
+                author: Some("Author, A".to_owned()),
                 tidied_note: "Highlight 1".to_owned(),
+                comment: Some("Question 1".to_owned()),
+                ..AppNote::default()
             },
             AppNote {
                 title: "Book A".to_owned(),
                 tidied_note: "Highlight 2".to_owned(),
+                ..AppNote::default()
             },
         ]
     }
@@ -194,71 +822,829 @@ mod tests {
         (format!("http://{addr}"), handle)
     }
 
+    /// Serves `(status, body)` responses in turn, one per connection
+    fn spawn_http_sequence(
+        responses: &'static [(&'static str, &'static str)],
+    ) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                stream
+                    .set_read_timeout(Some(Duration::from_secs(2)))
+                    .unwrap();
+                requests.push(read_http_request(&mut stream));
+                let response = format!(
+                    "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        });
+        (format!("http://{addr}"), handle)
+    }
+
     fn spawn_json_server(response_body: &'static str) -> (String, thread::JoinHandle<String>) {
         spawn_server("200 OK", response_body)
     }
 
+    fn request_action(request: &str) -> String {
+        // `action` is serialized first, so this is the top-level action
+        request
+            .split_once(r#""action""#)
+            .map(|(_, rest)| rest.trim_start_matches([':', ' ', '"']))
+            .and_then(|rest| rest.split_once('"'))
+            .map(|(action, _)| action.to_owned())
+            .unwrap_or_default()
+    }
+
+    /// Serves canned responses by action name until the test ends. Several
+    /// responses for the same action are served in turn, the last one being
+    /// repeated.
+    fn spawn_anki(responses: &[(&'static str, &'static str)]) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let responses = responses.to_vec();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&requests);
+        thread::spawn(move || {
+            let mut served: HashMap<String, usize> = HashMap::new();
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                stream
+                    .set_read_timeout(Some(Duration::from_secs(2)))
+                    .unwrap();
+                let request = read_http_request(&mut stream);
+                let action = request_action(&request);
+                let candidates: Vec<&str> = responses
+                    .iter()
+                    .filter(|(name, _)| *name == action)
+                    .map(|(_, body)| *body)
+                    .collect();
+                let count = served.entry(action).or_default();
+                let body = candidates
+                    .get(*count)
+                    .or(candidates.last())
+                    .copied()
+                    .unwrap_or(r#"{"result":null,"error":"unsupported action"}"#);
+                *count += 1;
+                recorded.lock().unwrap().push(request);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (format!("http://{addr}"), requests)
+    }
+
+    const DECK_NAMES: (&str, &str) = (
+        "deckNames",
+        r#"{"result":["Default","Kindle"],"error":null}"#,
+    );
+    const MODEL_NAMES: (&str, &str) = ("modelNames", r#"{"result":["Basique"],"error":null}"#);
+    const MODEL_FIELD_NAMES: (&str, &str) = (
+        "modelFieldNames",
+        r#"{"result":["Recto","Verso"],"error":null}"#,
+    );
+    const NOTE_1_INFO: &str = r#"{"result":[{"noteId":1496198395707,"fields":{"Recto":{"value":"Book A","order":0},"Verso":{"value":"Highlight 1","order":1}},"tags":[]}],"error":null}"#;
+    const NONE_PRESENT: &str =
+        r#"{"result":[{"result":[],"error":null},{"result":[],"error":null}],"error":null}"#;
+
     #[test]
     fn build_request_uses_config_and_allows_duplicates() {
         let config = AnkiConfig {
             deck: "Clippings".to_owned(),
+            deck_template: None,
             model: "Basic".to_owned(),
+            fields: indexmap::IndexMap::new(),
             front_field: "Front".to_owned(),
             back_field: "Back".to_owned(),
+            author_field: None,
+            comment_field: None,
+            location_field: None,
+            date_field: None,
             url: "http://localhost:8765".to_owned(),
+            api_key: None,
+            batch_size: std::num::NonZeroUsize::MIN,
+            retries: 0,
+            retry_delay_ms: 0,
+            duplicate_scope: DuplicateScope::Deck,
+            tags: vec!["kindle".to_owned(), "book::{title_slug}".to_owned()],
+            create_model: false,
         };
         let notes = sample_notes();
-        let req = build_add_notes_request(&notes, &config);
+        let req = build_add_notes_request(notes.iter().collect(), &config);
 
         assert_eq!(req.action, "addNotes");
         assert_eq!(req.version, 6);
         assert_eq!(req.params.notes.len(), 2);
         assert_eq!(req.params.notes[0].deck_name, "Clippings");
         assert_eq!(req.params.notes[0].model_name, "Basic");
+        assert_eq!(req.params.notes[0].tags, ["kindle", "book::book-a"]);
         assert_eq!(
-            req.params.notes[0].fields.get("Front").copied(),
+            req.params.notes[0].fields.get("Front").map(String::as_str),
             Some("Book A")
         );
         assert_eq!(
-            req.params.notes[0].fields.get("Back").copied(),
+            req.params.notes[0].fields.get("Back").map(String::as_str),
             Some("Highlight 1")
         );
         assert_eq!(
-            req.params.notes[1].fields.get("Back").copied(),
+            req.params.notes[1].fields.get("Back").map(String::as_str),
             Some("Highlight 2")
         );
+        assert_eq!(req.params.notes[0].fields.len(), 2);
         assert!(req.params.notes[0].options.allow_duplicate);
-        assert_eq!(req.params.notes[0].options.duplicate_scope, "deck");
+        assert_eq!(
+            req.params.notes[0].options.duplicate_scope,
+            DuplicateScope::Deck
+        );
+    }
+
+    #[test]
+    fn build_request_fills_optional_author_and_comment_fields() {
+        let config = AnkiConfig {
+            author_field: Some("Author".to_owned()),
+            comment_field: Some("Comment".to_owned()),
+            ..AnkiConfig::default()
+        };
+        let notes = sample_notes();
+        let req = build_add_notes_request(notes.iter().collect(), &config);
+
+        assert_eq!(
+            req.params.notes[0].fields.get("Author").map(String::as_str),
+            Some("Author, A")
+        );
+        assert_eq!(
+            req.params.notes[1].fields.get("Author").map(String::as_str),
+            Some("")
+        );
+        assert_eq!(
+            req.params.notes[0]
+                .fields
+                .get("Comment")
+                .map(String::as_str),
+            Some("Question 1")
+        );
+        assert_eq!(
+            req.params.notes[1]
+                .fields
+                .get("Comment")
+                .map(String::as_str),
+            Some("")
+        );
+    }
+
+    #[test]
+    fn duplicate_query_matches_deck_and_text_field() {
+        let note = AppNote {
+            title: "Book \"A\"".to_owned(),
+            tidied_note: "50% *off* \"my_var\"".to_owned(),
+            ..AppNote::default()
+        };
+        let config = AnkiConfig::default();
+        assert_eq!(
+            duplicate_query(&note, &config).unwrap(),
+            r#""deck:Kindle" "Verso:50% \*off\* \"my\_var\"""#
+        );
+    }
+
+    #[test]
+    fn fields_and_duplicate_query_use_html() {
+        let note = AppNote {
+            title: "Q&A".to_owned(),
+            tidied_note: "Line 1\nx < y & z".to_owned(),
+            ..AppNote::default()
+        };
+        let config = AnkiConfig::default();
+        assert_eq!(
+            duplicate_query(&note, &config).unwrap(),
+            r#""deck:Kindle" "Verso:Line 1<br>x &lt; y &amp; z""#
+        );
+        let fields = note_fields(&note, &config);
+        assert_eq!(fields["Recto"], "Q&amp;A");
+        assert_eq!(fields["Verso"], "Line 1<br>x &lt; y &amp; z");
+    }
+
+    #[test]
+    fn duplicate_query_ignores_fields_shared_by_the_book() {
+        let mut config = AnkiConfig::default();
+        for (name, template) in [("Source", "{title}"), ("Author", "{author}")] {
+            config.fields.insert(name.to_owned(), template.to_owned());
+        }
+        let notes = sample_notes();
+        assert_eq!(duplicate_query(&notes[0], &config), None);
+        let mut located = notes[0].clone();
+        located.location = Some(crate::note::Location { start: 7, end: 9 });
+        assert_eq!(
+            duplicate_query(&located, &config).unwrap(),
+            format!(r#""deck:Kindle" "tag:{}""#, id_tag(&located).unwrap())
+        );
+
+        config
+            .fields
+            .insert("Quote".to_owned(), "« {text} »".to_owned());
+        assert_eq!(
+            duplicate_query(&notes[0], &config).unwrap(),
+            r#""deck:Kindle" "Quote:« Highlight 1 »""#
+        );
+    }
+
+    #[test]
+    fn duplicate_query_uses_deck_of_the_note() {
+        let config = AnkiConfig {
+            deck_template: Some("{deck}::{title}".to_owned()),
+            ..AnkiConfig::default()
+        };
+        let query = duplicate_query(&sample_notes()[1], &config).unwrap();
+        assert!(query.starts_with(r#""deck:Kindle::Book A" "#), "{query}");
+    }
+
+    #[test]
+    fn field_templates_fill_note_and_duplicate_query() {
+        let mut config = AnkiConfig::default();
+        for (name, template) in [
+            ("Quote", "{text}"),
+            ("Source", "{title}, {author}"),
+            ("Comment", "{comment}"),
+        ] {
+            config.fields.insert(name.to_owned(), template.to_owned());
+        }
+        let notes = sample_notes();
+        let note = fill_note_api_params(&notes[0], &config);
+        assert_eq!(note.fields.len(), 3);
+        assert_eq!(
+            note.fields.get("Source").map(String::as_str),
+            Some("Book A, Author, A")
+        );
+        assert_eq!(
+            note.fields.get("Comment").map(String::as_str),
+            Some("Question 1")
+        );
+        assert_eq!(
+            duplicate_query(&notes[0], &config).unwrap(),
+            r#""deck:Kindle" "Quote:Highlight 1""#
+        );
+        assert_eq!(
+            check_field_names(&["Quote".to_owned(), "Source".to_owned()], &config)
+                .unwrap_err()
+                .to_string(),
+            r#"Note type Basique has no field named as configured in [anki]: "Comment". Valid field names: Quote, Source"#
+        );
+    }
+
+    #[test]
+    fn duplicate_query_also_matches_stable_id() {
+        let mut note = sample_notes().swap_remove(1);
+        note.location = Some(crate::note::Location { start: 7, end: 9 });
+        let tag = id_tag(&note).unwrap();
+        assert!(tag.starts_with("kindle-id::"));
+        assert_eq!(
+            duplicate_query(&note, &AnkiConfig::default()).unwrap(),
+            format!(r#""deck:Kindle" ("tag:{tag}" OR "Verso:Highlight 2")"#)
+        );
+        let config = AnkiConfig {
+            tags: vec!["kindle".to_owned()],
+            ..AnkiConfig::default()
+        };
+        assert_eq!(fill_note_api_params(&note, &config).tags, ["kindle", &tag]);
+    }
+
+    #[test]
+    fn clippings_sharing_a_location_start_match_distinct_notes() {
+        let mut notes = sample_notes();
+        notes[0].location = Some(crate::note::Location { start: 7, end: 7 });
+        notes[1].location = Some(crate::note::Location { start: 7, end: 9 });
+        notes[1].author = Some("Author, A".to_owned());
+        assert_eq!(id_tag(&notes[0]), id_tag(&notes[1]));
+
+        crate::my_clippings_parser::mark_shared_starts(&mut notes);
+        assert_ne!(id_tag(&notes[0]), id_tag(&notes[1]));
+        let config = AnkiConfig::default();
+        assert_ne!(
+            duplicate_query(&notes[0], &config),
+            duplicate_query(&notes[1], &config)
+        );
+    }
+
+    #[test]
+    fn duplicate_query_in_collection_scope_ignores_deck() {
+        let config = AnkiConfig {
+            duplicate_scope: DuplicateScope::Collection,
+            ..AnkiConfig::default()
+        };
+        assert_eq!(
+            duplicate_query(&sample_notes()[0], &config).unwrap(),
+            r#""Verso:Highlight 1""#
+        );
     }
 
     #[test]
     fn add_notes_posts_to_configured_url() {
-        let (url, server) = spawn_json_server(r#"{"result":[1,2],"error":null}"#);
+        let (url, requests) = spawn_anki(&[
+            DECK_NAMES,
+            MODEL_NAMES,
+            MODEL_FIELD_NAMES,
+            ("multi", NONE_PRESENT),
+            ("addNotes", r#"{"result":[1,2],"error":null}"#),
+        ]);
         let config = AnkiConfig {
             url,
             ..AnkiConfig::default()
         };
-        let created = add_notes(&sample_notes(), &config).unwrap();
-        assert_eq!(created, 2);
-        let request = server.join().unwrap();
-        assert!(request.starts_with("POST /"));
-        assert!(request.contains("addNotes"));
-        assert!(request.contains("allowDuplicate"));
+        let summary = add_notes(&sample_notes(), &config).unwrap();
+        assert_eq!(
+            summary,
+            ImportSummary {
+                created: 2,
+                ..ImportSummary::default()
+            }
+        );
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 5);
+        assert!(requests[0].starts_with("POST /"));
+        assert!(requests[3].contains("findNotes"));
+        assert!(requests[4].contains("addNotes"));
+        assert!(requests[4].contains("allowDuplicate"));
     }
 
     #[test]
-    fn add_notes_reports_partial_failure() {
-        let (url, server) = spawn_json_server(r#"{"result":[1,null],"error":null}"#);
+    fn add_notes_sends_api_key_with_every_action() {
+        let (url, requests) = spawn_anki(&[
+            DECK_NAMES,
+            MODEL_NAMES,
+            MODEL_FIELD_NAMES,
+            ("multi", NONE_PRESENT),
+            ("addNotes", r#"{"result":[1,2],"error":null}"#),
+        ]);
+        let config = AnkiConfig {
+            url,
+            api_key: Some("s3cret".to_owned()),
+            ..AnkiConfig::default()
+        };
+        add_notes(&sample_notes(), &config).unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 5);
+        for request in requests.iter() {
+            assert!(request.contains(r#""key": "s3cret""#), "{request}");
+        }
+        // Top-level `multi` request and its two `findNotes` actions
+        assert_eq!(requests[3].matches(r#""key": "s3cret""#).count(), 3);
+    }
+
+    #[test]
+    fn requests_have_no_key_by_default() {
+        let (url, requests) = spawn_anki(&[DECK_NAMES]);
+        let config = AnkiConfig {
+            url,
+            ..AnkiConfig::default()
+        };
+        let _: Vec<String> =
+            invoke(&new_agent(), &config, &request("deckNames", NoParams {})).unwrap();
+        assert!(!requests.lock().unwrap()[0].contains(r#""key""#));
+    }
+
+    #[test]
+    fn add_notes_skips_notes_already_in_anki() {
+        let (url, requests) = spawn_anki(&[
+            DECK_NAMES,
+            MODEL_NAMES,
+            MODEL_FIELD_NAMES,
+            (
+                "multi",
+                r#"{"result":[{"result":[1496198395707],"error":null},{"result":[],"error":null}],"error":null}"#,
+            ),
+            ("notesInfo", NOTE_1_INFO),
+            ("addNotes", r#"{"result":[3],"error":null}"#),
+        ]);
+        let config = AnkiConfig {
+            url,
+            ..AnkiConfig::default()
+        };
+        let summary = add_notes(&sample_notes(), &config).unwrap();
+        assert_eq!(
+            summary,
+            ImportSummary {
+                created: 1,
+                updated: 0,
+                unchanged: 1
+            }
+        );
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 6);
+        assert!(requests[4].contains("1496198395707"));
+        assert!(!requests[5].contains("Highlight 1"));
+        assert!(requests[5].contains("Highlight 2"));
+    }
+
+    #[test]
+    fn add_notes_updates_changed_notes_and_tags_their_id() {
+        let (url, requests) = spawn_anki(&[
+            DECK_NAMES,
+            MODEL_NAMES,
+            MODEL_FIELD_NAMES,
+            (
+                "multi",
+                r#"{"result":[{"result":[1496198395707],"error":null}],"error":null}"#,
+            ),
+            ("notesInfo", NOTE_1_INFO),
+            (
+                "multi",
+                r#"{"result":[{"result":null,"error":null}],"error":null}"#,
+            ),
+            (
+                "multi",
+                r#"{"result":[{"result":null,"error":null}],"error":null}"#,
+            ),
+        ]);
+        let config = AnkiConfig {
+            url,
+            ..AnkiConfig::default()
+        };
+        let mut extended = sample_notes().swap_remove(0);
+        extended.tidied_note = "Highlight 1, extended".to_owned();
+        extended.location = Some(crate::note::Location {
+            start: 100,
+            end: 104,
+        });
+        let tag = id_tag(&extended).unwrap();
+        let summary = add_notes(&[extended], &config).unwrap();
+        assert_eq!(
+            summary,
+            ImportSummary {
+                updated: 1,
+                ..ImportSummary::default()
+            }
+        );
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 7);
+        assert!(requests[3].contains(&format!("tag:{tag}")));
+        assert!(requests[5].contains("updateNoteFields"));
+        assert!(requests[5].contains("Highlight 1, extended"));
+        assert!(requests[6].contains("addTags"));
+        assert!(requests[6].contains(&tag));
+    }
+
+    #[test]
+    fn add_notes_reports_update_error() {
+        let (url, _requests) = spawn_anki(&[
+            DECK_NAMES,
+            MODEL_NAMES,
+            MODEL_FIELD_NAMES,
+            (
+                "multi",
+                r#"{"result":[{"result":[1496198395707],"error":null}],"error":null}"#,
+            ),
+            (
+                "notesInfo",
+                r#"{"result":[{"noteId":1496198395707,"fields":{"Recto":{"value":"Book A","order":0},"Verso":{"value":"Old","order":1}},"tags":[]}],"error":null}"#,
+            ),
+            (
+                "multi",
+                r#"{"result":[{"result":null,"error":"note was not found"}],"error":null}"#,
+            ),
+        ]);
+        let config = AnkiConfig {
+            url,
+            ..AnkiConfig::default()
+        };
+        let err = add_notes(&sample_notes()[..1], &config).unwrap_err();
+        assert_eq!(err.to_string(), "Failed to update note fields");
+        assert!(format!("{err:#}").contains("note was not found"));
+    }
+
+    #[test]
+    fn add_notes_makes_no_add_request_when_all_present() {
+        let (url, requests) = spawn_anki(&[
+            DECK_NAMES,
+            MODEL_NAMES,
+            MODEL_FIELD_NAMES,
+            (
+                "multi",
+                r#"{"result":[{"result":[1],"error":null},{"result":[2],"error":null}],"error":null}"#,
+            ),
+            (
+                "notesInfo",
+                r#"{"result":[
+                    {"noteId":1,"fields":{"Recto":{"value":"Book A","order":0},"Verso":{"value":"Highlight 1","order":1}},"tags":[]},
+                    {"noteId":2,"fields":{"Recto":{"value":"Book A","order":0},"Verso":{"value":"Highlight 2","order":1}},"tags":[]}
+                ],"error":null}"#,
+            ),
+        ]);
+        let config = AnkiConfig {
+            url,
+            ..AnkiConfig::default()
+        };
+        let summary = add_notes(&sample_notes(), &config).unwrap();
+        assert_eq!(
+            summary,
+            ImportSummary {
+                unchanged: 2,
+                ..ImportSummary::default()
+            }
+        );
+        assert_eq!(requests.lock().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn add_notes_reports_find_notes_error() {
+        let (url, _requests) = spawn_anki(&[
+            DECK_NAMES,
+            MODEL_NAMES,
+            MODEL_FIELD_NAMES,
+            (
+                "multi",
+                r#"{"result":[{"result":null,"error":"invalid search"},{"result":[],"error":null}],"error":null}"#,
+            ),
+        ]);
         let config = AnkiConfig {
             url,
             ..AnkiConfig::default()
         };
         let err = add_notes(&sample_notes(), &config).unwrap_err();
         assert!(
-            err.to_string().contains("1/2 succeeded"),
+            err.to_string().contains("invalid search"),
             "unexpected error: {err}"
         );
-        server.join().unwrap();
+    }
+
+    #[test]
+    fn build_request_fills_optional_location_and_date_fields() {
+        let config = AnkiConfig {
+            location_field: Some("Location".to_owned()),
+            date_field: Some("Date".to_owned()),
+            ..AnkiConfig::default()
+        };
+        let mut notes = sample_notes();
+        notes[0].location = Some(crate::note::Location {
+            start: 100,
+            end: 101,
+        });
+        notes[0].added_on = Some("Added on Someday".to_owned());
+        let req = build_add_notes_request(notes.iter().collect(), &config);
+
+        assert_eq!(
+            req.params.notes[0]
+                .fields
+                .get("Location")
+                .map(String::as_str),
+            Some("100-101")
+        );
+        assert_eq!(
+            req.params.notes[0].fields.get("Date").map(String::as_str),
+            Some("Added on Someday")
+        );
+        assert_eq!(
+            req.params.notes[1]
+                .fields
+                .get("Location")
+                .map(String::as_str),
+            Some("")
+        );
+    }
+
+    #[test]
+    fn add_notes_creates_missing_deck() {
+        let (url, requests) = spawn_anki(&[
+            ("deckNames", r#"{"result":["Default"],"error":null}"#),
+            ("createDeck", r#"{"result":1519323742721,"error":null}"#),
+            MODEL_NAMES,
+            MODEL_FIELD_NAMES,
+            ("multi", NONE_PRESENT),
+            ("addNotes", r#"{"result":[1,2],"error":null}"#),
+        ]);
+        let config = AnkiConfig {
+            url,
+            ..AnkiConfig::default()
+        };
+        add_notes(&sample_notes(), &config).unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(request_action(&requests[3]), "createDeck");
+        assert!(requests[3].contains("Kindle"));
+    }
+
+    #[test]
+    fn add_notes_creates_one_subdeck_per_book() {
+        let (url, requests) = spawn_anki(&[
+            DECK_NAMES,
+            ("createDeck", r#"{"result":1519323742721,"error":null}"#),
+            MODEL_NAMES,
+            MODEL_FIELD_NAMES,
+            ("multi", NONE_PRESENT),
+            ("addNotes", r#"{"result":[1,2],"error":null}"#),
+        ]);
+        let config = AnkiConfig {
+            url,
+            deck_template: Some("{deck}::{title}".to_owned()),
+            ..AnkiConfig::default()
+        };
+        let mut notes = sample_notes();
+        notes[1].title = "Book B".to_owned();
+        add_notes(&notes, &config).unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(request_action(&requests[3]), "createDeck");
+        assert!(requests[3].contains("Kindle::Book A"));
+        assert_eq!(request_action(&requests[4]), "createDeck");
+        assert!(requests[4].contains("Kindle::Book B"));
+        assert!(requests[6].contains(r#""deckName": "Kindle::Book B""#));
+    }
+
+    #[test]
+    fn add_notes_fails_clearly_on_missing_model() {
+        let (url, requests) = spawn_anki(&[
+            DECK_NAMES,
+            ("modelNames", r#"{"result":["Basic"],"error":null}"#),
+        ]);
+        let config = AnkiConfig {
+            url,
+            ..AnkiConfig::default()
+        };
+        let err = add_notes(&sample_notes(), &config).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Note type Basique does not exist in Anki"),
+            "unexpected error: {err}"
+        );
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn add_notes_creates_missing_model_when_enabled() {
+        let (url, requests) = spawn_anki(&[
+            DECK_NAMES,
+            ("modelNames", r#"{"result":["Basic"],"error":null}"#),
+            ("createModel", r#"{"result":{"id":1},"error":null}"#),
+            (
+                "modelFieldNames",
+                r#"{"result":["Title","Author","Highlight","Note","Location","Date"],"error":null}"#,
+            ),
+            ("multi", NONE_PRESENT),
+            ("addNotes", r#"{"result":[1,2],"error":null}"#),
+        ]);
+        let config = AnkiConfig {
+            url,
+            model: "Kindle Highlight".to_owned(),
+            front_field: "Title".to_owned(),
+            back_field: "Highlight".to_owned(),
+            create_model: true,
+            ..AnkiConfig::default()
+        };
+        add_notes(&sample_notes(), &config).unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(request_action(&requests[1]), "createModel");
+        assert!(requests[1].contains("inOrderFields"));
+        assert!(requests[1].contains("{{Highlight}}"));
+    }
+
+    #[test]
+    fn add_notes_rejects_unknown_field_names() {
+        let (url, requests) = spawn_anki(&[DECK_NAMES, MODEL_NAMES, MODEL_FIELD_NAMES]);
+        let config = AnkiConfig {
+            url,
+            back_field: "Vesro".to_owned(),
+            author_field: Some("Auteur".to_owned()),
+            deck_template: Some("{deck}::{title}".to_owned()),
+            ..AnkiConfig::default()
+        };
+        let err = add_notes(&sample_notes(), &config).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Note type Basique has no field named as configured in [anki]: back_field = \"Vesro\", author_field = \"Auteur\". Valid field names: Recto, Verso"
+        );
+        // Nothing is created before the check, not even the missing subdecks
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].contains("\"modelName\": \"Basique\""));
+    }
+
+    #[test]
+    fn add_notes_checks_fields_of_the_created_model_before_creating_it() {
+        let (url, requests) = spawn_anki(&[("modelNames", r#"{"result":["Basic"],"error":null}"#)]);
+        let config = AnkiConfig {
+            url,
+            model: "Kindle Highlight".to_owned(),
+            create_model: true,
+            ..AnkiConfig::default()
+        };
+        let err = add_notes(&sample_notes(), &config).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Note type Kindle Highlight has no field named"),
+            "unexpected error: {err}"
+        );
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn create_model_params_use_bundled_fields() {
+        let params = build_create_model_params("Kindle Highlight");
+        assert_eq!(params.model_name, "Kindle Highlight");
+        assert_eq!(
+            params.in_order_fields,
+            ["Title", "Author", "Highlight", "Note", "Location", "Date"]
+        );
+        for field in MODEL_FIELDS {
+            let placeholder = format!("{{{{{field}}}}}");
+            assert!(
+                MODEL_FRONT.contains(&placeholder) || MODEL_BACK.contains(&placeholder),
+                "{field} is not shown on the card"
+            );
+        }
+    }
+
+    #[test]
+    fn add_notes_reports_partial_failure() {
+        let (url, _requests) = spawn_anki(&[
+            DECK_NAMES,
+            MODEL_NAMES,
+            MODEL_FIELD_NAMES,
+            ("multi", NONE_PRESENT),
+            ("addNotes", r#"{"result":[1,null],"error":null}"#),
+        ]);
+        let config = AnkiConfig {
+            url,
+            ..AnkiConfig::default()
+        };
+        let err = add_notes(&sample_notes(), &config).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"Some notes could not be created in batch 1/1 (new notes 1-2 of 2) (1/2 succeeded): no note created before, not created: Book A "Highlight 2""#
+        );
+    }
+
+    #[test]
+    fn add_notes_sends_batches() {
+        let (url, requests) = spawn_anki(&[
+            DECK_NAMES,
+            MODEL_NAMES,
+            MODEL_FIELD_NAMES,
+            (
+                "multi",
+                r#"{"result":[{"result":[],"error":null}],"error":null}"#,
+            ),
+            ("addNotes", r#"{"result":[1],"error":null}"#),
+            ("addNotes", r#"{"result":[2],"error":null}"#),
+        ]);
+        let config = AnkiConfig {
+            url,
+            batch_size: std::num::NonZeroUsize::MIN,
+            ..AnkiConfig::default()
+        };
+        let summary = add_notes(&sample_notes(), &config).unwrap();
+        assert_eq!(summary.created, 2);
+        let requests = requests.lock().unwrap();
+        let actions: Vec<String> = requests.iter().map(|r| request_action(r)).collect();
+        assert_eq!(
+            actions,
+            [
+                "modelNames",
+                "modelFieldNames",
+                "deckNames",
+                "multi",
+                "multi",
+                "addNotes",
+                "addNotes"
+            ]
+        );
+        assert!(requests[5].contains("Highlight 1") && !requests[5].contains("Highlight 2"));
+        assert!(requests[6].contains("Highlight 2"));
+    }
+
+    #[test]
+    fn add_notes_reports_failed_batch() {
+        let (url, _requests) = spawn_anki(&[
+            DECK_NAMES,
+            MODEL_NAMES,
+            MODEL_FIELD_NAMES,
+            (
+                "multi",
+                r#"{"result":[{"result":[],"error":null}],"error":null}"#,
+            ),
+            ("addNotes", r#"{"result":[1],"error":null}"#),
+            (
+                "addNotes",
+                r#"{"result":null,"error":"collection is not available"}"#,
+            ),
+        ]);
+        let config = AnkiConfig {
+            url,
+            batch_size: std::num::NonZeroUsize::MIN,
+            ..AnkiConfig::default()
+        };
+        let mut notes = sample_notes();
+        notes.push(notes[1].clone());
+        let err = add_notes(&notes, &config).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "Failed to send batch 2/3 (new notes 2-2 of 3): new notes 1-1 created, the notes of this batch may or may not have been created; new notes 3-3 not sent, run the import again to send them: AnkiConnect error: collection is not available"
+        );
     }
 
     #[test]
@@ -268,8 +1654,8 @@ mod tests {
             url: "http://127.0.0.1:1".to_owned(),
             ..AnkiConfig::default()
         };
-        let created = add_notes(&[], &config).unwrap();
-        assert_eq!(created, 0);
+        let summary = add_notes(&[], &config).unwrap();
+        assert_eq!(summary, ImportSummary::default());
     }
 
     #[test]
@@ -295,6 +1681,7 @@ mod tests {
         );
         let config = AnkiConfig {
             url,
+            retries: 0,
             ..AnkiConfig::default()
         };
         let err = add_notes(&sample_notes(), &config).unwrap_err();
@@ -304,4 +1691,106 @@ mod tests {
         );
         server.join().unwrap();
     }
+
+    #[test]
+    fn invoke_retries_server_errors() {
+        let (url, server) = spawn_http_sequence(&[
+            ("503 Service Unavailable", ""),
+            ("500 Internal Server Error", ""),
+            ("200 OK", r#"{"result":["Default"],"error":null}"#),
+        ]);
+        let config = AnkiConfig {
+            url,
+            retry_delay_ms: 1,
+            ..AnkiConfig::default()
+        };
+        let decks: Vec<String> =
+            invoke(&new_agent(), &config, &request("deckNames", NoParams {})).unwrap();
+        assert_eq!(decks, ["Default"]);
+        assert_eq!(server.join().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn invoke_gives_up_after_configured_retries() {
+        let (url, server) = spawn_http_sequence(&[
+            ("503 Service Unavailable", ""),
+            ("503 Service Unavailable", ""),
+        ]);
+        let config = AnkiConfig {
+            url,
+            retries: 1,
+            retry_delay_ms: 1,
+            ..AnkiConfig::default()
+        };
+        let err =
+            invoke::<_, Vec<String>>(&new_agent(), &config, &request("deckNames", NoParams {}))
+                .unwrap_err();
+        assert!(
+            err.to_string().contains("HTTP 503"),
+            "unexpected error: {err}"
+        );
+        assert_eq!(server.join().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn add_notes_is_not_retried_after_server_error() {
+        assert!(!is_transient(&ureq::Error::StatusCode(500), "addNotes"));
+        assert!(!is_transient(
+            &ureq::Error::Io(io::Error::from(ErrorKind::TimedOut)),
+            "addNotes"
+        ));
+        assert!(is_transient(
+            &ureq::Error::Io(io::Error::from(ErrorKind::ConnectionRefused)),
+            "addNotes"
+        ));
+        assert!(is_transient(&ureq::Error::StatusCode(502), "multi"));
+        assert!(!is_transient(&ureq::Error::StatusCode(404), "multi"));
+    }
+
+    #[test]
+    fn wait_for_anki_returns_once_anki_answers() {
+        let (url, requests) = spawn_anki(&[("version", r#"{"result":6,"error":null}"#)]);
+        let config = AnkiConfig {
+            url,
+            ..AnkiConfig::default()
+        };
+        wait_for_anki(&config, Duration::from_secs(5)).unwrap();
+        assert_eq!(request_action(&requests.lock().unwrap()[0]), "version");
+    }
+
+    #[test]
+    fn wait_for_anki_fails_right_away_on_anki_error() {
+        let (url, requests) = spawn_anki(&[(
+            "version",
+            r#"{"result":null,"error":"valid api key must be provided"}"#,
+        )]);
+        let config = AnkiConfig {
+            url,
+            ..AnkiConfig::default()
+        };
+        let err = wait_for_anki(&config, Duration::from_secs(30)).unwrap_err();
+        assert!(
+            err.to_string().contains("valid api key must be provided"),
+            "unexpected error: {err}"
+        );
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn wait_for_anki_times_out() {
+        // Nothing listens on a port just released
+        let url = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let config = AnkiConfig {
+            url: url.clone(),
+            ..AnkiConfig::default()
+        };
+        let err = wait_for_anki(&config, Duration::ZERO).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("AnkiConnect at {url} did not answer within 0 seconds")
+        );
+    }
 }
@@ -1,11 +1,198 @@
+use crate::app_config::{AnkiConfig, CsvColumn, CsvConfig};
 use crate::note::Note;
-use anyhow::Result;
+use anyhow::{Result, bail};
 use std::io::Write;
 
-pub fn write_csv(notes: &[Note], writer: impl Write) -> Result<usize> {
-    let mut wtr = csv::Writer::from_writer(writer);
+/// Content of a CSV column
+enum Value<'a> {
+    Template(&'a str),
+    Tags,
+    Deck,
+}
+
+struct Column<'a> {
+    name: &'a str,
+    value: Value<'a>,
+}
+
+/// Columns of the output: the `[csv]` columns, or else the field columns
+/// and the tags when tag templates are configured. With Anki headers, the
+/// field columns are only the fields of the note type mapped in `[anki]`,
+/// the `[csv]` columns are named after them, and a deck column is added
+/// when the deck depends on the book.
+fn columns<'a>(csv: &'a CsvConfig, anki: &'a AnkiConfig) -> Result<Vec<Column<'a>>> {
+    let mut columns = if csv.columns.is_empty() {
+        let fields = if csv.anki_headers {
+            anki.mapped_fields()
+                .into_iter()
+                .map(|field| (field.name, field.template))
+                .collect()
+        } else {
+            field_columns(anki)
+        };
+        let mut columns: Vec<Column> = fields
+            .into_iter()
+            .map(|(name, template)| Column {
+                name,
+                value: Value::Template(template),
+            })
+            .collect();
+        if !anki.tags.is_empty() {
+            columns.push(Column {
+                name: "Tags",
+                value: Value::Tags,
+            });
+        }
+        columns
+    } else {
+        let mut columns: Vec<Column> = csv
+            .columns
+            .iter()
+            .map(|&column| csv_column(column))
+            .collect();
+        if csv.anki_headers {
+            name_after_fields(&mut columns, anki)?;
+        }
+        columns
+    };
+    if csv.anki_headers && anki.deck_template.is_some() {
+        columns.push(Column {
+            name: "Deck",
+            value: Value::Deck,
+        });
+    }
+    Ok(columns)
+}
+
+/// Names the `[csv]` columns after the mapped fields rendering the same
+/// value, since Anki matches the `#columns:` names with the note type fields
+fn name_after_fields<'a>(columns: &mut [Column<'a>], anki: &'a AnkiConfig) -> Result<()> {
+    let fields = anki.mapped_fields();
+    for column in columns {
+        let Value::Template(template) = column.value else {
+            continue;
+        };
+        let Some(field) = fields.iter().find(|field| field.template == template) else {
+            bail!(
+                "The {} column fills no field of note type {} mapped in [anki], drop it from the columns or map it to a field to use Anki headers",
+                column.name.to_lowercase(),
+                anki.model
+            );
+        };
+        column.name = field.name;
+    }
+    Ok(())
+}
+
+/// Field columns: the `[anki.fields]` table, or else title, note, author
+/// and comment, named after the `*_field` keys
+fn field_columns(config: &AnkiConfig) -> Vec<(&str, &str)> {
+    if !config.fields.is_empty() {
+        return config
+            .fields
+            .iter()
+            .map(|(name, template)| (name.as_str(), template.as_str()))
+            .collect();
+    }
+    vec![
+        (config.front_field.as_str(), "{title}"),
+        (config.back_field.as_str(), "{text}"),
+        (
+            config.author_field.as_deref().unwrap_or("Author"),
+            "{author}",
+        ),
+        (
+            config.comment_field.as_deref().unwrap_or("Comment"),
+            "{comment}",
+        ),
+    ]
+}
+
+fn csv_column(column: CsvColumn) -> Column<'static> {
+    let (name, template) = match column {
+        CsvColumn::Title => ("Title", "{title}"),
+        CsvColumn::Author => ("Author", "{author}"),
+        CsvColumn::Text => ("Text", "{text}"),
+        CsvColumn::Comment => ("Comment", "{comment}"),
+        CsvColumn::Page => ("Page", "{page}"),
+        CsvColumn::Location => ("Location", "{location}"),
+        CsvColumn::Date => ("Date", "{date}"),
+        CsvColumn::Kind => ("Kind", "{kind}"),
+        CsvColumn::Tags => {
+            return Column {
+                name: "Tags",
+                value: Value::Tags,
+            };
+        }
+    };
+    Column {
+        name,
+        value: Value::Template(template),
+    }
+}
+
+/// Name of a delimiter in the `#separator:` Anki header
+fn separator_name(delimiter: u8) -> Result<&'static str> {
+    Ok(match delimiter {
+        b',' => "Comma",
+        b';' => "Semicolon",
+        b'\t' => "Tab",
+        b' ' => "Space",
+        b'|' => "Pipe",
+        b':' => "Colon",
+        other => bail!(
+            "Anki headers do not support the {:?} delimiter, use a comma, semicolon, tab, space, pipe or colon",
+            char::from(other)
+        ),
+    })
+}
+
+/// Writes one row per note, see [`CsvConfig`]. With `anki_headers`, the
+/// file starts with the header lines of Anki 2.1.55+ naming the note type,
+/// deck and columns, which replace the header row.
+pub fn write_csv(
+    notes: &[Note],
+    csv: &CsvConfig,
+    anki: &AnkiConfig,
+    mut writer: impl Write,
+) -> Result<usize> {
+    let columns = columns(csv, anki)?;
+    let names: Vec<&str> = columns.iter().map(|column| column.name).collect();
+    let delimiter = csv.delimiter.0;
+    if csv.anki_headers {
+        writeln!(writer, "#separator:{}", separator_name(delimiter)?)?;
+        writeln!(writer, "#html:false")?;
+        writeln!(writer, "#notetype:{}", anki.model)?;
+        let position = |name: &str| names.iter().position(|&other| other == name);
+        if let Some(index) = position("Tags") {
+            writeln!(writer, "#tags column:{}", index + 1)?;
+        }
+        match position("Deck") {
+            Some(index) => writeln!(writer, "#deck column:{}", index + 1)?,
+            None => writeln!(writer, "#deck:{}", anki.deck)?,
+        }
+        writeln!(
+            writer,
+            "#columns:{}",
+            names.join(&char::from(delimiter).to_string())
+        )?;
+    }
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(delimiter)
+        .from_writer(writer);
+    if csv.header && !csv.anki_headers {
+        wtr.write_record(&names)?;
+    }
     for note in notes {
-        wtr.write_record([&note.title, &note.tidied_note])?;
+        let record: Vec<String> = columns
+            .iter()
+            .map(|column| match column.value {
+                Value::Template(template) => note.render(template),
+                Value::Tags => note.tags(&anki.tags).join(" "),
+                Value::Deck => anki.deck_name(note),
+            })
+            .collect();
+        wtr.write_record(&record)?;
     }
     wtr.flush()?;
     Ok(notes.len())
@@ -14,31 +201,227 @@ pub fn write_csv(notes: &[Note], writer: impl Write) -> Result<usize> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::app_config::Delimiter;
     use crate::note::Note;
+    use crate::note::fixtures::dune;
+
+    const ANKI_HEADERS: CsvConfig = CsvConfig {
+        columns: Vec::new(),
+        delimiter: Delimiter(b','),
+        header: false,
+        anki_headers: true,
+    };
+
+    fn notes() -> Vec<Note> {
+        vec![Note {
+            page: Some(12),
+            ..dune(100, "Fear is the mind-killer.")
+        }]
+    }
 
     #[test]
-    fn writes_title_and_note_rows() {
+    fn writes_title_note_author_and_comment_rows() {
         let notes = vec![
             Note {
                 title: "Book".to_owned(),
+                author: Some("Author, A".to_owned()),
+                comment: Some("Why?".to_owned()),
                 tidied_note: "Line 1\nLine 2".to_owned(),
+                ..Note::default()
             },
             Note {
                 title: "Other".to_owned(),
                 tidied_note: "Highlight".to_owned(),
+                ..Note::default()
             },
         ];
         let mut buf = Vec::new();
-        let count = write_csv(&notes, &mut buf).unwrap();
+        let count = write_csv(
+            &notes,
+            &CsvConfig::default(),
+            &AnkiConfig::default(),
+            &mut buf,
+        )
+        .unwrap();
         assert_eq!(count, 2);
         let csv = String::from_utf8(buf).unwrap();
-        assert_eq!(csv, "Book,\"Line 1\nLine 2\"\nOther,Highlight\n");
+        assert_eq!(
+            csv,
+            "Book,\"Line 1\nLine 2\",\"Author, A\",Why?\nOther,Highlight,,\n"
+        );
+    }
+
+    #[test]
+    fn appends_tags_column_when_configured() {
+        let notes = vec![Note {
+            title: "Dune".to_owned(),
+            tidied_note: "Fear is the mind-killer.".to_owned(),
+            ..Note::default()
+        }];
+        let config = AnkiConfig {
+            tags: ["kindle", "book::{title_slug}", "author::{author_slug}"]
+                .map(str::to_owned)
+                .to_vec(),
+            ..AnkiConfig::default()
+        };
+        let mut buf = Vec::new();
+        write_csv(&notes, &CsvConfig::default(), &config, &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "Dune,Fear is the mind-killer.,,,kindle book::dune\n"
+        );
+    }
+
+    #[test]
+    fn writes_configured_field_templates_in_order() {
+        let notes = vec![Note {
+            title: "Dune".to_owned(),
+            author: Some("Herbert, Frank".to_owned()),
+            tidied_note: "Fear is the mind-killer.".to_owned(),
+            page: Some(12),
+            ..Note::default()
+        }];
+        let mut config = AnkiConfig::default();
+        config
+            .fields
+            .insert("Quote".to_owned(), "{text}".to_owned());
+        config
+            .fields
+            .insert("Source".to_owned(), "{title} (p. {page})".to_owned());
+        let mut buf = Vec::new();
+        write_csv(&notes, &CsvConfig::default(), &config, &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "Fear is the mind-killer.,Dune (p. 12)\n"
+        );
+    }
+
+    #[test]
+    fn prepends_anki_headers() {
+        let notes = vec![Note {
+            title: "Dune".to_owned(),
+            tidied_note: "Fear is the mind-killer.".to_owned(),
+            ..Note::default()
+        }];
+        let mut buf = Vec::new();
+        write_csv(&notes, &ANKI_HEADERS, &AnkiConfig::default(), &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "#separator:Comma\n#html:false\n#notetype:Basique\n#deck:Kindle\n#columns:Recto,Verso\nDune,Fear is the mind-killer.\n"
+        );
+    }
+
+    #[test]
+    fn anki_headers_only_name_mapped_fields() {
+        let config = AnkiConfig {
+            author_field: Some("Auteur".to_owned()),
+            date_field: Some("Date".to_owned()),
+            ..AnkiConfig::default()
+        };
+        let mut buf = Vec::new();
+        write_csv(&notes(), &ANKI_HEADERS, &config, &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "#separator:Comma\n#html:false\n#notetype:Basique\n#deck:Kindle\n#columns:Recto,Verso,Auteur,Date\nDune,Fear is the mind-killer.,\"Herbert, Frank\",\n"
+        );
+    }
+
+    #[test]
+    fn anki_headers_point_to_tags_and_deck_columns() {
+        let notes = vec![Note {
+            title: "Dune".to_owned(),
+            tidied_note: "Fear is the mind-killer.".to_owned(),
+            ..Note::default()
+        }];
+        let mut config = AnkiConfig {
+            deck_template: Some("{deck}::{title}".to_owned()),
+            tags: vec!["kindle".to_owned()],
+            ..AnkiConfig::default()
+        };
+        config
+            .fields
+            .insert("Quote".to_owned(), "{text}".to_owned());
+        let mut buf = Vec::new();
+        write_csv(&notes, &ANKI_HEADERS, &config, &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "#separator:Comma\n#html:false\n#notetype:Basique\n#tags column:2\n#deck column:3\n#columns:Quote,Tags,Deck\nFear is the mind-killer.,kindle,Kindle::Dune\n"
+        );
+    }
+
+    #[test]
+    fn writes_selected_columns_with_header_and_delimiter() {
+        let csv = CsvConfig {
+            columns: vec![
+                CsvColumn::Page,
+                CsvColumn::Text,
+                CsvColumn::Kind,
+                CsvColumn::Tags,
+            ],
+            delimiter: Delimiter(b';'),
+            header: true,
+            anki_headers: false,
+        };
+        let anki = AnkiConfig {
+            tags: vec!["kindle".to_owned()],
+            ..AnkiConfig::default()
+        };
+        let mut buf = Vec::new();
+        write_csv(&notes(), &csv, &anki, &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "Page;Text;Kind;Tags\n12;Fear is the mind-killer.;highlight;kindle\n"
+        );
+    }
+
+    #[test]
+    fn anki_headers_follow_delimiter_and_replace_header_row() {
+        let csv = CsvConfig {
+            columns: vec![CsvColumn::Title, CsvColumn::Author],
+            delimiter: Delimiter(b'\t'),
+            header: true,
+            anki_headers: true,
+        };
+        let anki = AnkiConfig {
+            author_field: Some("Auteur".to_owned()),
+            ..AnkiConfig::default()
+        };
+        let mut buf = Vec::new();
+        write_csv(&notes(), &csv, &anki, &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "#separator:Tab\n#html:false\n#notetype:Basique\n#deck:Kindle\n#columns:Recto\tAuteur\nDune\tHerbert, Frank\n"
+        );
+    }
+
+    #[test]
+    fn anki_headers_reject_columns_filling_no_field() {
+        let csv = CsvConfig {
+            columns: vec![CsvColumn::Title, CsvColumn::Text, CsvColumn::Page],
+            ..ANKI_HEADERS
+        };
+        let err = write_csv(&notes(), &csv, &AnkiConfig::default(), Vec::new()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The page column fills no field of note type Basique mapped in [anki], drop it from the columns or map it to a field to use Anki headers"
+        );
+    }
+
+    #[test]
+    fn anki_headers_reject_unsupported_delimiter() {
+        let csv = CsvConfig {
+            delimiter: Delimiter(b'#'),
+            ..ANKI_HEADERS
+        };
+        let err = write_csv(&notes(), &csv, &AnkiConfig::default(), Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("'#' delimiter"), "{err}");
     }
 
     #[test]
     fn empty_notes_writes_nothing() {
         let mut buf = Vec::new();
-        let count = write_csv(&[], &mut buf).unwrap();
+        let count =
+            write_csv(&[], &CsvConfig::default(), &AnkiConfig::default(), &mut buf).unwrap();
         assert_eq!(count, 0);
         assert!(buf.is_empty());
     }
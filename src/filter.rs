@@ -0,0 +1,268 @@
+use crate::app_config::FilterConfig;
+use crate::note::Note;
+use anyhow::{Context, Result};
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use regex::{Regex, RegexBuilder};
+use std::collections::HashSet;
+
+const DATE_FORMAT: &str = "%Y-%m-%d";
+const DATE_TIME_FORMATS: [&str; 3] = ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M:%S"];
+
+/// Parses a `--since` bound: a date starts at midnight
+pub fn parse_since(value: &str) -> Result<NaiveDateTime, String> {
+    parse_bound(value, NaiveTime::MIN)
+}
+
+/// Parses an `--until` bound: a date includes the whole day
+pub fn parse_until(value: &str) -> Result<NaiveDateTime, String> {
+    let end_of_day = NaiveTime::from_hms_opt(23, 59, 59).unwrap_or(NaiveTime::MIN);
+    parse_bound(value, end_of_day)
+}
+
+fn parse_bound(value: &str, time_of_day: NaiveTime) -> Result<NaiveDateTime, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, DATE_FORMAT) {
+        return Ok(date.and_time(time_of_day));
+    }
+    DATE_TIME_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .ok_or_else(|| {
+            format!("invalid date `{value}`, expected YYYY-MM-DD or YYYY-MM-DDTHH:MM[:SS]")
+        })
+}
+
+/// Keeps the notes added within `since..=until`. Notes whose date could not
+/// be parsed are dropped as soon as a bound is given.
+pub fn by_date(
+    notes: Vec<Note>,
+    since: Option<NaiveDateTime>,
+    until: Option<NaiveDateTime>,
+) -> Vec<Note> {
+    if since.is_none() && until.is_none() {
+        return notes;
+    }
+    notes
+        .into_iter()
+        .filter(|note| {
+            note.added_at.is_some_and(|added_at| {
+                since.is_none_or(|since| since <= added_at)
+                    && until.is_none_or(|until| added_at <= until)
+            })
+        })
+        .collect()
+}
+
+/// Keeps every note of the books that have a note in `selected`, for the
+/// outputs that rewrite whole books
+pub fn whole_books(notes: Vec<Note>, selected: &[Note]) -> Vec<Note> {
+    let books: HashSet<(&str, Option<&str>)> = selected
+        .iter()
+        .map(|note| (note.title.as_str(), note.author.as_deref()))
+        .collect();
+    notes
+        .into_iter()
+        .filter(|note| books.contains(&(note.title.as_str(), note.author.as_deref())))
+        .collect()
+}
+
+/// A case-insensitive substring, or a regular expression written `/.../`
+#[derive(Debug)]
+enum Pattern {
+    Substring(String),
+    Regex(Regex),
+}
+
+impl Pattern {
+    fn new(pattern: &str) -> Result<Self> {
+        match pattern
+            .strip_prefix('/')
+            .and_then(|rest| rest.strip_suffix('/'))
+        {
+            Some(regex) => RegexBuilder::new(regex)
+                .case_insensitive(true)
+                .build()
+                .map(Self::Regex)
+                .with_context(|| format!("Invalid regular expression: {pattern}")),
+            None => Ok(Self::Substring(pattern.to_lowercase())),
+        }
+    }
+
+    fn is_match(&self, text: &str) -> bool {
+        match self {
+            Self::Substring(substring) => text.to_lowercase().contains(substring),
+            Self::Regex(regex) => regex.is_match(text),
+        }
+    }
+}
+
+/// Selection of books by title and author, see [`FilterConfig`]
+#[derive(Debug)]
+pub struct BookFilter {
+    books: Vec<Pattern>,
+    exclude_books: Vec<Pattern>,
+    authors: Vec<Pattern>,
+}
+
+impl BookFilter {
+    pub fn new(config: &FilterConfig) -> Result<Self> {
+        let compile = |patterns: &[String]| -> Result<Vec<Pattern>> {
+            patterns
+                .iter()
+                .map(|pattern| Pattern::new(pattern))
+                .collect()
+        };
+        Ok(Self {
+            books: compile(&config.books)?,
+            exclude_books: compile(&config.exclude_books)?,
+            authors: compile(&config.authors)?,
+        })
+    }
+
+    pub fn apply(&self, notes: Vec<Note>) -> Vec<Note> {
+        notes.into_iter().filter(|note| self.keeps(note)).collect()
+    }
+
+    fn keeps(&self, note: &Note) -> bool {
+        let author = note.author.as_deref().unwrap_or_default();
+        (self.books.is_empty() || self.books.iter().any(|book| book.is_match(&note.title)))
+            && !self
+                .exclude_books
+                .iter()
+                .any(|book| book.is_match(&note.title))
+            && (self.authors.is_empty()
+                || self.authors.iter().any(|pattern| pattern.is_match(author)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_added_at(text: &str, added_at: Option<&str>) -> Note {
+        Note {
+            tidied_note: text.to_owned(),
+            added_at: added_at.map(|at| parse_since(at).unwrap()),
+            ..Note::default()
+        }
+    }
+
+    fn texts(notes: &[Note]) -> Vec<&str> {
+        notes.iter().map(|note| note.tidied_note.as_str()).collect()
+    }
+
+    #[test]
+    fn parses_date_and_date_time_bounds() {
+        assert_eq!(
+            parse_since("2020-01-02").unwrap().to_string(),
+            "2020-01-02 00:00:00"
+        );
+        assert_eq!(
+            parse_until("2020-01-02").unwrap().to_string(),
+            "2020-01-02 23:59:59"
+        );
+        assert_eq!(
+            parse_until("2020-01-02T10:30").unwrap().to_string(),
+            "2020-01-02 10:30:00"
+        );
+        assert!(parse_since("02/01/2020").is_err());
+    }
+
+    #[test]
+    fn keeps_notes_within_inclusive_range() {
+        let notes = vec![
+            note_added_at("before", Some("2020-01-01T23:59:59")),
+            note_added_at("first day", Some("2020-01-02")),
+            note_added_at("last day", Some("2020-01-05T23:00")),
+            note_added_at("after", Some("2020-01-06")),
+            note_added_at("undated", None),
+        ];
+        let kept = by_date(
+            notes,
+            Some(parse_since("2020-01-02").unwrap()),
+            Some(parse_until("2020-01-05").unwrap()),
+        );
+        assert_eq!(texts(&kept), ["first day", "last day"]);
+    }
+
+    fn book(title: &str, author: Option<&str>) -> Note {
+        Note {
+            title: title.to_owned(),
+            author: author.map(str::to_owned),
+            tidied_note: title.to_owned(),
+            ..Note::default()
+        }
+    }
+
+    fn sample_books() -> Vec<Note> {
+        vec![
+            book("Dune", Some("Herbert, Frank")),
+            book("The Hobbit", Some("Tolkien, J. R. R.")),
+            book("Le Monde - 01/01/2020", None),
+        ]
+    }
+
+    #[test]
+    fn empty_book_filter_keeps_everything() {
+        let filter = BookFilter::new(&FilterConfig::default()).unwrap();
+        assert_eq!(filter.apply(sample_books()).len(), 3);
+    }
+
+    #[test]
+    fn includes_books_by_substring_or_regex() {
+        let filter = BookFilter::new(&FilterConfig {
+            books: vec!["dune".to_owned(), "/^the /".to_owned()],
+            ..FilterConfig::default()
+        })
+        .unwrap();
+        assert_eq!(texts(&filter.apply(sample_books())), ["Dune", "The Hobbit"]);
+    }
+
+    #[test]
+    fn excludes_books() {
+        let filter = BookFilter::new(&FilterConfig {
+            exclude_books: vec!["/^le monde/".to_owned()],
+            ..FilterConfig::default()
+        })
+        .unwrap();
+        assert_eq!(texts(&filter.apply(sample_books())), ["Dune", "The Hobbit"]);
+    }
+
+    #[test]
+    fn filters_by_author() {
+        let filter = BookFilter::new(&FilterConfig {
+            authors: vec!["TOLKIEN".to_owned()],
+            ..FilterConfig::default()
+        })
+        .unwrap();
+        assert_eq!(texts(&filter.apply(sample_books())), ["The Hobbit"]);
+    }
+
+    #[test]
+    fn rejects_invalid_regex() {
+        let err = BookFilter::new(&FilterConfig {
+            books: vec!["/(/".to_owned()],
+            ..FilterConfig::default()
+        })
+        .unwrap_err();
+        assert!(err.to_string().contains("Invalid regular expression: /(/"));
+    }
+
+    #[test]
+    fn whole_books_keeps_every_note_of_selected_books() {
+        let mut notes = sample_books();
+        let mut older = book("Dune", Some("Herbert, Frank"));
+        older.tidied_note = "Older Dune highlight".to_owned();
+        notes.push(older);
+        let selected = [book("Dune", Some("Herbert, Frank"))];
+        assert_eq!(
+            texts(&whole_books(notes, &selected)),
+            ["Dune", "Older Dune highlight"]
+        );
+    }
+
+    #[test]
+    fn no_bounds_keeps_everything() {
+        let notes = vec![note_added_at("undated", None)];
+        assert_eq!(by_date(notes, None, None).len(), 1);
+    }
+}
@@ -0,0 +1,206 @@
+use crate::note::{Location, Note};
+use anyhow::Result;
+use serde::{Serialize, Serializer};
+use std::io::Write;
+
+/// Version of the JSON output, bumped when a field is renamed, removed or
+/// changes meaning; new fields may be added within a version
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// JSON document holding every note
+#[derive(Serialize)]
+struct Document<'a> {
+    schema_version: u32,
+    notes: Records<'a>,
+}
+
+/// Notes serialized as an array of records, one at a time
+struct Records<'a>(&'a [Note]);
+
+impl Serialize for Records<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter().map(Record::from))
+    }
+}
+
+/// A note, as a JSON object
+#[derive(Serialize)]
+struct Record<'a> {
+    /// Stable id of the clipping, see [`Note::stable_id`]
+    id: Option<String>,
+    book: &'a str,
+    author: Option<&'a str>,
+    kind: &'static str,
+    text: &'a str,
+    comment: Option<&'a str>,
+    page: Option<u32>,
+    location: Option<RecordLocation>,
+    /// "Added on" timestamp as `YYYY-MM-DDTHH:MM:SS`, in the device local time
+    added_at: Option<String>,
+    /// Raw, localized "Added on" text
+    added_on: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct RecordLocation {
+    start: u32,
+    end: u32,
+}
+
+impl<'a> From<&'a Note> for Record<'a> {
+    fn from(note: &'a Note) -> Self {
+        Self {
+            id: note.stable_id(),
+            book: &note.title,
+            author: note.author.as_deref(),
+            kind: note.kind.as_str(),
+            text: &note.tidied_note,
+            comment: note.comment.as_deref(),
+            page: note.page,
+            location: note
+                .location
+                .map(|Location { start, end }| RecordLocation { start, end }),
+            added_at: note
+                .added_at
+                .map(|added_at| added_at.format("%Y-%m-%dT%H:%M:%S").to_string()),
+            added_on: note.added_on.as_deref(),
+        }
+    }
+}
+
+/// Writes a single JSON document: `{"schema_version": 1, "notes": [...]}`,
+/// serializing the notes one at a time
+pub fn write_json(notes: &[Note], mut writer: impl Write) -> Result<usize> {
+    let document = Document {
+        schema_version: SCHEMA_VERSION,
+        notes: Records(notes),
+    };
+    serde_json::to_writer_pretty(&mut writer, &document)?;
+    writeln!(writer)?;
+    writer.flush()?;
+    Ok(notes.len())
+}
+
+/// Writes one JSON object per line, each carrying its `schema_version`,
+/// serializing the notes one at a time
+pub fn write_jsonl(notes: &[Note], mut writer: impl Write) -> Result<usize> {
+    #[derive(Serialize)]
+    struct Line<'a> {
+        schema_version: u32,
+        #[serde(flatten)]
+        record: Record<'a>,
+    }
+
+    for note in notes {
+        let line = Line {
+            schema_version: SCHEMA_VERSION,
+            record: Record::from(note),
+        };
+        serde_json::to_writer(&mut writer, &line)?;
+        writeln!(writer)?;
+    }
+    writer.flush()?;
+    Ok(notes.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::note::ClippingKind;
+    use serde_json::{Value, json};
+
+    fn notes() -> Vec<Note> {
+        vec![
+            Note {
+                title: "Dune".to_owned(),
+                author: Some("Herbert, Frank".to_owned()),
+                tidied_note: "Fear is the mind-killer.".to_owned(),
+                comment: Some("Litany".to_owned()),
+                page: Some(12),
+                location: Some(Location {
+                    start: 100,
+                    end: 102,
+                }),
+                added_on: Some("Added on Monday, 1 January 2024 10:00:00".to_owned()),
+                added_at: chrono::NaiveDate::from_ymd_opt(2024, 1, 1)
+                    .and_then(|date| date.and_hms_opt(10, 0, 0)),
+                ..Note::default()
+            },
+            Note {
+                title: "Other".to_owned(),
+                tidied_note: "A note".to_owned(),
+                kind: ClippingKind::Note,
+                ..Note::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn writes_versioned_document() {
+        let notes = notes();
+        let mut buf = Vec::new();
+        assert_eq!(write_json(&notes, &mut buf).unwrap(), 2);
+        let document: Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(
+            document,
+            json!({
+                "schema_version": 1,
+                "notes": [
+                    {
+                        "id": notes[0].stable_id(),
+                        "book": "Dune",
+                        "author": "Herbert, Frank",
+                        "kind": "highlight",
+                        "text": "Fear is the mind-killer.",
+                        "comment": "Litany",
+                        "page": 12,
+                        "location": {"start": 100, "end": 102},
+                        "added_at": "2024-01-01T10:00:00",
+                        "added_on": "Added on Monday, 1 January 2024 10:00:00",
+                    },
+                    {
+                        "id": null,
+                        "book": "Other",
+                        "author": null,
+                        "kind": "note",
+                        "text": "A note",
+                        "comment": null,
+                        "page": null,
+                        "location": null,
+                        "added_at": null,
+                        "added_on": null,
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn writes_one_object_per_line() {
+        let mut buf = Vec::new();
+        assert_eq!(write_jsonl(&notes(), &mut buf).unwrap(), 2);
+        let output = String::from_utf8(buf).unwrap();
+        let lines: Vec<Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["schema_version"], 1);
+        assert_eq!(lines[0]["location"]["end"], 102);
+        assert_eq!(lines[1]["book"], "Other");
+        assert!(output.ends_with("}\n"));
+    }
+
+    #[test]
+    fn empty_notes_write_empty_document_or_nothing() {
+        let mut buf = Vec::new();
+        write_json(&[], &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "{\n  \"schema_version\": 1,\n  \"notes\": []\n}\n"
+        );
+        let mut buf = Vec::new();
+        write_jsonl(&[], &mut buf).unwrap();
+        assert!(buf.is_empty());
+    }
+}
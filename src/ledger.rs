@@ -0,0 +1,174 @@
+use crate::note::Note;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+const LEDGER_FILE: &str = "ledger.toml";
+
+/// Content hashes of the notes already exported, per output target
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+struct LedgerState {
+    #[serde(default)]
+    targets: BTreeMap<String, BTreeSet<String>>,
+}
+
+/// Persistent record of exported notes, so that each run only emits new clippings
+pub struct Ledger {
+    path: PathBuf,
+    state: LedgerState,
+}
+
+impl Ledger {
+    /// `kindlenotes2anki/ledger.toml` in the user data directory
+    /// (`$XDG_DATA_HOME` on Linux)
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::data_dir().map(|dir| dir.join(env!("CARGO_PKG_NAME")).join(LEDGER_FILE))
+    }
+
+    /// Loads the ledger at `path`, a missing file being an empty ledger
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let state = match fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents)
+                .with_context(|| format!("Failed to parse ledger file: {}", path.display()))?,
+            Err(err) if err.kind() == ErrorKind::NotFound => LedgerState::default(),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Failed to read ledger file: {}", path.display()));
+            }
+        };
+        Ok(Self { path, state })
+    }
+
+    /// Forgets every note recorded for `target`
+    pub fn reset(&mut self, target: &str) {
+        self.state.targets.remove(target);
+    }
+
+    /// Drops the notes already recorded for `target`
+    pub fn retain_new(&self, target: &str, notes: Vec<Note>) -> Vec<Note> {
+        let Some(known) = self.state.targets.get(target) else {
+            return notes;
+        };
+        notes
+            .into_iter()
+            .filter(|note| !known.contains(&note.content_hash()))
+            .collect()
+    }
+
+    pub fn record(&mut self, target: &str, notes: &[Note]) {
+        self.state
+            .targets
+            .entry(target.to_owned())
+            .or_default()
+            .extend(notes.iter().map(Note::content_hash));
+    }
+
+    /// Writes the ledger to a temporary file next to it, then renames it
+    /// over the ledger, so that an interrupted run never truncates it
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).with_context(|| {
+                format!("Failed to create ledger directory: {}", parent.display())
+            })?;
+        }
+        let contents = toml::to_string(&self.state).context("Failed to serialize ledger")?;
+        let temporary = self.temporary_path();
+        fs::write(&temporary, contents)
+            .with_context(|| format!("Failed to write ledger file: {}", temporary.display()))?;
+        fs::rename(&temporary, &self.path)
+            .with_context(|| format!("Failed to replace ledger file: {}", self.path.display()))
+    }
+
+    /// `ledger.toml.tmp` next to `ledger.toml`
+    fn temporary_path(&self) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_owned();
+        name.push(".tmp");
+        self.path.with_file_name(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn note(text: &str) -> Note {
+        Note {
+            title: "Book".to_owned(),
+            tidied_note: text.to_owned(),
+            ..Note::default()
+        }
+    }
+
+    fn texts(notes: &[Note]) -> Vec<&str> {
+        notes.iter().map(|note| note.tidied_note.as_str()).collect()
+    }
+
+    #[test]
+    fn missing_file_is_an_empty_ledger() {
+        let dir = TempDir::new().unwrap();
+        let ledger = Ledger::open(dir.path().join("ledger.toml")).unwrap();
+        assert_eq!(ledger.retain_new("csv", vec![note("a")]).len(), 1);
+    }
+
+    #[test]
+    fn recorded_notes_are_skipped_after_reload() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("nested").join("ledger.toml");
+        let mut ledger = Ledger::open(path.clone()).unwrap();
+        ledger.record("csv", &[note("a")]);
+        ledger.save().unwrap();
+
+        let ledger = Ledger::open(path).unwrap();
+        let new = ledger.retain_new("csv", vec![note("a"), note("b")]);
+        assert_eq!(texts(&new), ["b"]);
+    }
+
+    #[test]
+    fn save_replaces_the_ledger_through_a_temporary_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("ledger.toml");
+        fs::write(&path, "[targets]\ncsv = []\n").unwrap();
+        let mut ledger = Ledger::open(path.clone()).unwrap();
+        ledger.record("json", &[note("a")]);
+        ledger.save().unwrap();
+
+        let names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["ledger.toml"]);
+        let ledger = Ledger::open(path).unwrap();
+        assert!(ledger.retain_new("json", vec![note("a")]).is_empty());
+    }
+
+    #[test]
+    fn targets_are_tracked_separately() {
+        let dir = TempDir::new().unwrap();
+        let mut ledger = Ledger::open(dir.path().join("ledger.toml")).unwrap();
+        ledger.record("csv", &[note("a")]);
+        let new = ledger.retain_new("anki:http://localhost:8765", vec![note("a")]);
+        assert_eq!(texts(&new), ["a"]);
+    }
+
+    #[test]
+    fn reset_forgets_target() {
+        let dir = TempDir::new().unwrap();
+        let mut ledger = Ledger::open(dir.path().join("ledger.toml")).unwrap();
+        ledger.record("csv", &[note("a")]);
+        ledger.reset("csv");
+        assert_eq!(ledger.retain_new("csv", vec![note("a")]).len(), 1);
+    }
+
+    #[test]
+    fn invalid_ledger_file_is_reported() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("ledger.toml");
+        fs::write(&path, "not = [valid").unwrap();
+        let err = Ledger::open(path).err().unwrap();
+        assert!(err.to_string().contains("Failed to parse ledger file"));
+    }
+}
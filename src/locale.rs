@@ -0,0 +1,483 @@
+use crate::app_config::ParserConfig;
+use chrono::{NaiveDate, NaiveDateTime};
+use clap::ValueEnum;
+
+/// Kindle UI languages with built-in parser presets
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Locale {
+    /// English
+    En,
+    /// French
+    Fr,
+    /// German
+    De,
+    /// Spanish
+    Es,
+    /// Italian
+    It,
+    /// Portuguese (Brazil)
+    Pt,
+    /// Dutch
+    Nl,
+    /// Japanese
+    Ja,
+    /// Chinese (Simplified)
+    Zh,
+}
+
+// Lowercase month names, January first
+const EN_MONTHS: [&str; 12] = [
+    "january",
+    "february",
+    "march",
+    "april",
+    "may",
+    "june",
+    "july",
+    "august",
+    "september",
+    "october",
+    "november",
+    "december",
+];
+const FR_MONTHS: [&str; 12] = [
+    "janvier",
+    "février",
+    "mars",
+    "avril",
+    "mai",
+    "juin",
+    "juillet",
+    "août",
+    "septembre",
+    "octobre",
+    "novembre",
+    "décembre",
+];
+const DE_MONTHS: [&str; 12] = [
+    "januar",
+    "februar",
+    "märz",
+    "april",
+    "mai",
+    "juni",
+    "juli",
+    "august",
+    "september",
+    "oktober",
+    "november",
+    "dezember",
+];
+const ES_MONTHS: [&str; 12] = [
+    "enero",
+    "febrero",
+    "marzo",
+    "abril",
+    "mayo",
+    "junio",
+    "julio",
+    "agosto",
+    "septiembre",
+    "octubre",
+    "noviembre",
+    "diciembre",
+];
+const IT_MONTHS: [&str; 12] = [
+    "gennaio",
+    "febbraio",
+    "marzo",
+    "aprile",
+    "maggio",
+    "giugno",
+    "luglio",
+    "agosto",
+    "settembre",
+    "ottobre",
+    "novembre",
+    "dicembre",
+];
+const PT_MONTHS: [&str; 12] = [
+    "janeiro",
+    "fevereiro",
+    "março",
+    "abril",
+    "maio",
+    "junho",
+    "julho",
+    "agosto",
+    "setembro",
+    "outubro",
+    "novembro",
+    "dezembro",
+];
+const NL_MONTHS: [&str; 12] = [
+    "januari",
+    "februari",
+    "maart",
+    "april",
+    "mei",
+    "juni",
+    "juli",
+    "augustus",
+    "september",
+    "oktober",
+    "november",
+    "december",
+];
+
+/// Marker strings of one Kindle UI language
+struct Preset {
+    bookmark: &'static str,
+    highlight: &'static str,
+    note: &'static str,
+    /// Notice injected when the clipping limit of a book is reached, only
+    /// set for the languages it was checked against a real file. The other
+    /// languages are left out on purpose rather than guessed, their notice
+    /// is filtered by adding it to the configured `ignored` patterns.
+    clipping_limit: Option<&'static str>,
+    page: &'static [&'static str],
+    location: &'static [&'static str],
+}
+
+impl Locale {
+    fn preset(self) -> Preset {
+        match self {
+            Self::En => Preset {
+                bookmark: "- Your Bookmark",
+                highlight: "- Your Highlight",
+                note: "- Your Note",
+                clipping_limit: Some("<You have reached the clipping limit"),
+                page: &["page"],
+                location: &["location"],
+            },
+            Self::Fr => Preset {
+                bookmark: "- Votre signet",
+                highlight: "- Votre surlignement",
+                note: "- Votre note",
+                clipping_limit: Some("<Vous avez atteint la limite maximale"),
+                page: &["page"],
+                location: &["emplacement"],
+            },
+            Self::De => Preset {
+                bookmark: "- Ihr Lesezeichen",
+                highlight: "- Ihre Markierung",
+                note: "- Ihre Notiz",
+                clipping_limit: None,
+                page: &["seite"],
+                location: &["position"],
+            },
+            Self::Es => Preset {
+                bookmark: "- Tu marcador",
+                highlight: "- Tu subrayado",
+                note: "- Tu nota",
+                clipping_limit: None,
+                page: &["página"],
+                location: &["posición"],
+            },
+            Self::It => Preset {
+                bookmark: "- Il tuo segnalibro",
+                highlight: "- La tua evidenziazione",
+                note: "- La tua nota",
+                clipping_limit: None,
+                page: &["pagina"],
+                location: &["posizione"],
+            },
+            Self::Pt => Preset {
+                bookmark: "- Seu marcador",
+                highlight: "- Seu destaque",
+                note: "- Sua nota",
+                clipping_limit: None,
+                page: &["página"],
+                location: &["posição"],
+            },
+            Self::Nl => Preset {
+                bookmark: "- Je bladwijzer",
+                highlight: "- Je markering",
+                note: "- Je notitie",
+                clipping_limit: None,
+                page: &["pagina"],
+                location: &["locatie"],
+            },
+            // Japanese and Chinese announce the kind at the end of the first
+            // segment: "- 位置No. 100-101のハイライト |作成日: ..."
+            Self::Ja => Preset {
+                bookmark: "のブックマーク",
+                highlight: "のハイライト",
+                note: "のメモ",
+                clipping_limit: None,
+                page: &["ページ"],
+                location: &["位置no."],
+            },
+            Self::Zh => Preset {
+                bookmark: "的书签",
+                highlight: "的标注",
+                note: "的笔记",
+                clipping_limit: None,
+                page: &["页"],
+                location: &["位置"],
+            },
+        }
+    }
+
+    /// Month number (1-based) of a lowercase month name in this language
+    fn month(self, name: &str) -> Option<u32> {
+        let months: &[&str] = match self {
+            Self::En => &EN_MONTHS,
+            Self::Fr => &FR_MONTHS,
+            Self::De => &DE_MONTHS,
+            Self::Es => &ES_MONTHS,
+            Self::It => &IT_MONTHS,
+            Self::Pt => &PT_MONTHS,
+            Self::Nl => &NL_MONTHS,
+            // Japanese and Chinese write numeric months: "2020年1月1日"
+            Self::Ja | Self::Zh => &[],
+        };
+        let position = months.iter().position(|&month| month == name)?;
+        u32::try_from(position + 1).ok()
+    }
+
+    /// Applies this language to the configured parser settings: its markers
+    /// replace the configured ones, while its clipping-limit notice and page
+    /// and location words are added to the configured ones
+    pub fn apply_to(self, config: &mut ParserConfig) {
+        let preset = self.parser_config();
+        config.bookmark = preset.bookmark;
+        config.highlight = preset.highlight;
+        config.note = preset.note;
+        for (configured, words) in [
+            (&mut config.ignored, preset.ignored),
+            (&mut config.page, preset.page),
+            (&mut config.location, preset.location),
+        ] {
+            for word in words {
+                if !configured.contains(&word) {
+                    configured.push(word);
+                }
+            }
+        }
+    }
+
+    /// Parser settings matching the `My Clippings.txt` of this language
+    pub fn parser_config(self) -> ParserConfig {
+        let preset = self.preset();
+        let words = |words: &[&str]| words.iter().map(|&word| word.to_owned()).collect();
+        ParserConfig {
+            bookmark: preset.bookmark.to_owned(),
+            highlight: preset.highlight.to_owned(),
+            note: preset.note.to_owned(),
+            ignored: preset
+                .clipping_limit
+                .map(str::to_owned)
+                .into_iter()
+                .collect(),
+            page: words(preset.page),
+            location: words(preset.location),
+        }
+    }
+}
+
+/// Markers of afternoon times in 12-hour clocks (English, Japanese, Chinese)
+const PM_MARKERS: [&str; 3] = ["pm", "午後", "下午"];
+const AM_MARKERS: [&str; 3] = ["am", "午前", "上午"];
+
+/// Parses the localized "Added on" part of a metadata line, in any supported
+/// language: "Added on Monday, January 1, 2020 10:00:00 AM",
+/// "Ajouté le samedi 20 octobre 2018 à 12:55:45" or
+/// "作成日: 2020年1月1日 水曜日 10:00:00"
+pub fn parse_timestamp(text: &str) -> Option<NaiveDateTime> {
+    let lowered = text.to_lowercase();
+    // Skip colons that are not part of the time, as in "作成日: "
+    let time_pos = lowered
+        .match_indices(':')
+        .map(|(pos, _)| pos)
+        .find(|&pos| pos > 0 && lowered.as_bytes()[pos - 1].is_ascii_digit())?;
+    let hour_start = lowered[..time_pos]
+        .trim_end_matches(|c: char| c.is_ascii_digit())
+        .len();
+    let time_end = lowered[time_pos..]
+        .find(|c: char| !c.is_ascii_digit() && c != ':')
+        .map_or(lowered.len(), |pos| time_pos + pos);
+    let mut time_parts = lowered[hour_start..time_end].split(':');
+    let mut hour: u32 = time_parts.next()?.parse().ok()?;
+    let minute: u32 = time_parts.next()?.parse().ok()?;
+    let second: u32 = time_parts.next().map_or(Some(0), |s| s.parse().ok())?;
+
+    let words: Vec<&str> = lowered
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|word| !word.is_empty())
+        .collect();
+    // Only markers right next to the time count: "am" is also a German
+    // word, as in "Hinzugefügt am Montag"
+    let before = lowered[..hour_start].trim_end();
+    let after = lowered[time_end..].trim_start();
+    let has_marker = |markers: &[&str]| {
+        markers.iter().any(|marker| {
+            after
+                .strip_prefix(marker)
+                .is_some_and(|rest| !rest.starts_with(char::is_alphabetic))
+                || before
+                    .strip_suffix(marker)
+                    .is_some_and(|rest| !rest.ends_with(char::is_alphabetic))
+        })
+    };
+    if has_marker(&PM_MARKERS) && hour < 12 {
+        hour += 12;
+    } else if has_marker(&AM_MARKERS) && hour == 12 {
+        hour = 0;
+    }
+
+    let date_text = format!("{} {}", &lowered[..hour_start], &lowered[time_end..]);
+    let (year, month, day) = if date_text.contains('年') {
+        (
+            number_before(&date_text, '年')?,
+            number_before(&date_text, '月')?,
+            number_before(&date_text, '日')?,
+        )
+    } else {
+        let month = words.iter().find_map(|word| {
+            Locale::value_variants()
+                .iter()
+                .find_map(|locale| locale.month(word))
+        })?;
+        let numbers: Vec<&str> = date_text
+            .split(|c: char| !c.is_ascii_digit())
+            .filter(|number| !number.is_empty())
+            .collect();
+        let year = numbers
+            .iter()
+            .find(|number| number.len() == 4)?
+            .parse()
+            .ok()?;
+        let day = numbers
+            .iter()
+            .find(|number| number.len() <= 2)?
+            .parse()
+            .ok()?;
+        (year, month, day)
+    };
+    NaiveDate::from_ymd_opt(i32::try_from(year).ok()?, month, day)?
+        .and_hms_opt(hour, minute, second)
+}
+
+/// Parses the first number right before `marker`, as in "2020年"
+fn number_before(text: &str, marker: char) -> Option<u32> {
+    text.match_indices(marker).find_map(|(pos, _)| {
+        let start = text[..pos]
+            .trim_end_matches(|c: char| c.is_ascii_digit())
+            .len();
+        text[start..pos].parse().ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn french_preset_matches_default_markers() {
+        let preset = Locale::Fr.parser_config();
+        let default = ParserConfig::default();
+        assert_eq!(preset.bookmark, default.bookmark);
+        assert_eq!(preset.highlight, default.highlight);
+        assert_eq!(preset.note, default.note);
+    }
+
+    fn timestamp(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(year, month, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_timestamp_in_every_language() {
+        let expected = timestamp(2020, 3, 4, 15, 30);
+        let texts = [
+            "Added on Wednesday, March 4, 2020 3:30:00 PM",
+            "Ajouté le mercredi 4 mars 2020 15:30:00",
+            "Hinzugefügt am Mittwoch, 4. März 2020 15:30:00",
+            "Añadido el miércoles, 4 de marzo de 2020 15:30:00",
+            "Aggiunto in data mercoledì 4 marzo 2020 15:30:00",
+            "Adicionado: quarta-feira, 4 de março de 2020 15:30:00",
+            "Toegevoegd op woensdag 4 maart 2020 15:30:00",
+            "作成日: 2020年3月4日 水曜日 15:30:00",
+            "作成日: 2020年3月4日 水曜日 午後3:30:00",
+            "添加于 2020年3月4日星期三 下午3:30:00",
+        ];
+        for text in texts {
+            assert_eq!(parse_timestamp(text), Some(expected), "{text}");
+        }
+    }
+
+    #[test]
+    fn parses_twelve_hour_clock_edges() {
+        assert_eq!(
+            parse_timestamp("Added on Monday, January 1, 2020 12:05:00 AM"),
+            Some(timestamp(2020, 1, 1, 0, 5))
+        );
+        assert_eq!(
+            parse_timestamp("Added on Monday, January 1, 2020 12:05:00 PM"),
+            Some(timestamp(2020, 1, 1, 12, 5))
+        );
+        assert_eq!(
+            parse_timestamp("Ajouté le samedi 20 octobre 2018 à 12:55:45"),
+            NaiveDate::from_ymd_opt(2018, 10, 20)
+                .unwrap()
+                .and_hms_opt(12, 55, 45)
+        );
+        // "am" is only a 12-hour clock marker next to the time
+        assert_eq!(
+            parse_timestamp("Hinzugefügt am Mittwoch, 4. März 2020 12:30:00"),
+            Some(timestamp(2020, 3, 4, 12, 30))
+        );
+        assert_eq!(
+            parse_timestamp("添加于 2020年3月4日星期三 上午12:30:00"),
+            Some(timestamp(2020, 3, 4, 0, 30))
+        );
+    }
+
+    #[test]
+    fn rejects_unparseable_timestamps() {
+        assert_eq!(parse_timestamp("Added on some day"), None);
+        assert_eq!(parse_timestamp("Added on Smarch 1, 2020 10:00:00"), None);
+        assert_eq!(parse_timestamp("Added on February 30, 2020 10:00:00"), None);
+    }
+
+    #[test]
+    fn locale_keeps_configured_ignored_patterns_and_words() {
+        let mut config: crate::app_config::AppConfig =
+            toml::from_str("[parser]\nignored = [\"<Sie haben\"]\nlocation = [\"pos.\"]\n")
+                .unwrap();
+        Locale::De.apply_to(&mut config.parser);
+        assert_eq!(config.parser.highlight, "- Ihre Markierung");
+        assert_eq!(config.parser.ignored, ["<Sie haben"]);
+        assert_eq!(config.parser.page, ["page", "seite"]);
+        assert_eq!(config.parser.location, ["pos.", "position"]);
+
+        Locale::En.apply_to(&mut config.parser);
+        assert_eq!(
+            config.parser.ignored,
+            ["<Sie haben", "<You have reached the clipping limit"]
+        );
+    }
+
+    #[test]
+    fn only_checked_presets_filter_the_clipping_limit_notice() {
+        let filtering: Vec<Locale> = Locale::value_variants()
+            .iter()
+            .copied()
+            .filter(|locale| !locale.parser_config().ignored.is_empty())
+            .collect();
+        assert_eq!(filtering, [Locale::En, Locale::Fr]);
+    }
+
+    #[test]
+    fn every_preset_has_distinct_markers() {
+        for &locale in Locale::value_variants() {
+            let config = locale.parser_config();
+            assert_ne!(config.highlight, config.note, "{locale:?}");
+            assert_ne!(config.highlight, config.bookmark, "{locale:?}");
+            assert_ne!(config.note, config.bookmark, "{locale:?}");
+        }
+    }
+}
@@ -1,51 +1,300 @@
+mod apkg;
 mod app_config;
 mod connect;
 mod csv_writer;
+mod filter;
+mod json_writer;
+mod ledger;
+mod locale;
+mod markdown;
 mod my_clippings_parser;
 mod note;
-use anyhow::{Context, Result};
-use app_config::AppConfig;
-use clap::Parser;
-use std::fs::File;
-use std::io::{self, Write};
+use anyhow::{Context, Result, anyhow, bail};
+use app_config::{API_KEY_ENV, AppConfig, CsvColumn, CsvConfig, Delimiter};
+use chrono::NaiveDateTime;
+use clap::{Parser, ValueEnum};
+use ledger::Ledger;
+use locale::Locale;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Cursor, Write};
 use std::path::PathBuf;
+use std::time::Duration;
+
+/// Format of the output written to stdout or `--output`
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Csv,
+    Json,
+    Jsonl,
+}
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
+#[allow(clippy::struct_excessive_bools)]
 struct Cli {
     /// The path to the clippings txt file to read
-    clippings: PathBuf,
+    #[arg(required_unless_present = "reset_ledger")]
+    clippings: Option<PathBuf>,
     /// Use `AnkiConnect`, if not provided will generate a CSV output
     #[arg(short, long)]
     use_anki_connect: bool,
-    /// Write CSV to this file instead of stdout (ignored with `--use-anki-connect`)
+    /// Wait up to SECONDS (default 60) for `AnkiConnect` to answer before importing
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        num_args = 0..=1,
+        default_missing_value = "60",
+        requires = "use_anki_connect"
+    )]
+    wait_for_anki: Option<u64>,
+    /// Format of the output written to stdout or `--output`
+    #[arg(long, value_enum, default_value_t = OutputFormat::Csv, conflicts_with_all = ["use_anki_connect", "apkg", "markdown"])]
+    format: OutputFormat,
+    /// Write the output to this file instead of stdout (ignored with `--use-anki-connect`)
     #[arg(short = 'o', long, value_name = "PATH")]
     output: Option<PathBuf>,
+    /// Write an Anki package (.apkg) with a bundled note type to this file, instead of CSV
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["use_anki_connect", "output", "anki_headers"])]
+    apkg: Option<PathBuf>,
+    /// Write one Markdown file per book into this directory, instead of CSV
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = ["use_anki_connect", "output", "anki_headers", "apkg"]
+    )]
+    markdown: Option<PathBuf>,
+    /// Start the CSV with header lines selecting the Anki note type, deck and columns (Anki 2.1.55+)
+    #[arg(long, conflicts_with = "use_anki_connect")]
+    anki_headers: bool,
+    /// CSV columns, in order, overrides the `[csv]` config
+    #[arg(long, value_enum, value_name = "COLUMN,...", value_delimiter = ',')]
+    columns: Vec<CsvColumn>,
+    /// CSV delimiter: a single printable character except the double quote, or `tab`
+    #[arg(long, value_name = "CHAR")]
+    delimiter: Option<Delimiter>,
+    /// Start the CSV with a row naming the columns
+    #[arg(long)]
+    header: bool,
     /// The path to a config file, if not provided will use defaults
     #[arg(long)]
     config: Option<PathBuf>,
+    /// Fallback language for entries whose language is not detected, replaces the `[parser]` markers
+    #[arg(long, value_enum)]
+    locale: Option<Locale>,
+    /// Keep every version of highlights edited on the device
+    #[arg(long)]
+    keep_duplicates: bool,
+    /// Only keep clippings added on or after this date (YYYY-MM-DD or YYYY-MM-DDTHH:MM)
+    #[arg(long, value_name = "DATE", value_parser = filter::parse_since)]
+    since: Option<NaiveDateTime>,
+    /// Only keep clippings added on or before this date (YYYY-MM-DD or YYYY-MM-DDTHH:MM)
+    #[arg(long, value_name = "DATE", value_parser = filter::parse_until)]
+    until: Option<NaiveDateTime>,
+    /// Only keep books whose title matches this substring or `/regex/` (repeatable)
+    #[arg(long = "book", value_name = "PATTERN")]
+    books: Vec<String>,
+    /// Drop books whose title matches this substring or `/regex/` (repeatable)
+    #[arg(long = "exclude-book", value_name = "PATTERN")]
+    exclude_books: Vec<String>,
+    /// Only keep books whose author matches this substring or `/regex/` (repeatable)
+    #[arg(long = "author", value_name = "PATTERN")]
+    authors: Vec<String>,
+    /// Export every clipping, without reading or updating the ledger of previous runs
+    #[arg(long, conflicts_with = "reset_ledger")]
+    full: bool,
+    /// The path to the ledger of exported clippings, defaults to the user data directory
+    #[arg(long, value_name = "PATH")]
+    ledger: Option<PathBuf>,
+    /// Forget the clippings recorded for the selected output, then export CLIPPINGS if given
+    #[arg(long, conflicts_with = "markdown")]
+    reset_ledger: bool,
+    /// Only print the clippings not printed by a previous run, when writing to stdout
+    #[arg(long, conflicts_with_all = ["full", "markdown"])]
+    only_new: bool,
+}
+
+impl Cli {
+    /// Key of the output in the ledger: each output file, stdout format
+    /// and `AnkiConnect` instance keeps its own history
+    fn ledger_target(&self, config: &AppConfig) -> Result<String> {
+        let format = match self.format {
+            OutputFormat::Csv => "csv",
+            OutputFormat::Json => "json",
+            OutputFormat::Jsonl => "jsonl",
+        };
+        let (kind, path) = if self.use_anki_connect {
+            return Ok(format!("anki:{}", config.anki.url));
+        } else if let Some(path) = &self.apkg {
+            ("apkg", path)
+        } else if let Some(dir) = &self.markdown {
+            ("markdown", dir)
+        } else if let Some(path) = &self.output {
+            (format, path)
+        } else {
+            return Ok(format.to_owned());
+        };
+        let path = std::path::absolute(path)
+            .with_context(|| format!("Failed to resolve output path: {}", path.display()))?;
+        Ok(format!("{kind}:{}", path.display()))
+    }
+
+    /// Whether the ledger is read and updated: not with `--full`, nor for
+    /// Markdown files rewritten whole, nor for stdout unless asked for
+    fn uses_ledger(&self) -> bool {
+        let stdout = !self.use_anki_connect
+            && self.apkg.is_none()
+            && self.markdown.is_none()
+            && self.output.is_none();
+        !self.full && self.markdown.is_none() && (!stdout || self.only_new || self.reset_ledger)
+    }
+
+    /// Rejects the CSV options given with another `--format`, and warns
+    /// about the `[csv]` config left unused
+    fn check_csv_options(&self, csv: &CsvConfig) -> Result<()> {
+        if self.format == OutputFormat::Csv {
+            return Ok(());
+        }
+        if self.anki_headers || self.header || self.delimiter.is_some() || !self.columns.is_empty()
+        {
+            bail!("--anki-headers, --columns, --delimiter and --header only apply to CSV output");
+        }
+        if *csv != CsvConfig::default() {
+            writeln!(
+                io::stderr(),
+                "Ignoring the [csv] config, which only applies to CSV output"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn open_ledger(path: Option<PathBuf>) -> Result<Ledger> {
+    let path = match path {
+        Some(path) => path,
+        None => Ledger::default_path()
+            .context("Failed to locate the user data directory, use --ledger")?,
+    };
+    Ledger::open(path)
 }
 
 fn main() -> Result<()> {
     let args = Cli::parse();
-    let config = AppConfig::new(args.config).context("Failed to initialize app config")?;
-    let notes = my_clippings_parser::parse_clippings(&args.clippings, &config)
+    let mut config =
+        AppConfig::new(args.config.clone()).context("Failed to initialize app config")?;
+    args.check_csv_options(&config.csv)?;
+    // An empty variable, as left by `export KINDLENOTES2ANKI_API_KEY=`, is unset
+    if let Some(api_key) = env::var_os(API_KEY_ENV).filter(|api_key| !api_key.is_empty()) {
+        let api_key = api_key
+            .into_string()
+            .map_err(|_| anyhow!("{API_KEY_ENV} is not valid UTF-8"))?;
+        config.anki.api_key = Some(api_key);
+    }
+    let target = args.ledger_target(&config)?;
+    let mut ledger = if args.uses_ledger() {
+        Some(open_ledger(args.ledger)?)
+    } else {
+        None
+    };
+    if args.reset_ledger
+        && let Some(ledger) = &mut ledger
+    {
+        ledger.reset(&target);
+        ledger.save()?;
+    }
+    let Some(clippings) = args.clippings else {
+        writeln!(io::stderr(), "Forgot the clippings exported to {target}")?;
+        return Ok(());
+    };
+    if let Some(locale) = args.locale {
+        locale.apply_to(&mut config.parser);
+    }
+    if !args.columns.is_empty() {
+        config.csv.columns = args.columns;
+    }
+    if let Some(delimiter) = args.delimiter {
+        config.csv.delimiter = delimiter;
+    }
+    config.csv.header |= args.header;
+    config.csv.anki_headers |= args.anki_headers;
+    config.filter.books.extend(args.books);
+    config.filter.exclude_books.extend(args.exclude_books);
+    config.filter.authors.extend(args.authors);
+    let book_filter = filter::BookFilter::new(&config.filter).context("Invalid book filter")?;
+    let mut notes = my_clippings_parser::parse_clippings(&clippings, &config)
         .context("Failed to parse clippings")?;
+    if !args.keep_duplicates {
+        notes = my_clippings_parser::dedupe_highlights(notes);
+    }
+    my_clippings_parser::mark_shared_starts(&mut notes);
+    let notes = if args.markdown.is_some() {
+        // Book files are rewritten whole, so the filters select books, not highlights
+        let selected = book_filter.apply(filter::by_date(notes.clone(), args.since, args.until));
+        filter::whole_books(notes, &selected)
+    } else {
+        book_filter.apply(filter::by_date(notes, args.since, args.until))
+    };
+    let notes = match &ledger {
+        Some(ledger) => ledger.retain_new(&target, notes),
+        None => notes,
+    };
+
     if args.use_anki_connect {
-        let count = connect::add_notes(&notes, &config.anki)
+        if let Some(seconds) = args.wait_for_anki {
+            connect::wait_for_anki(&config.anki, Duration::from_secs(seconds))?;
+        }
+        let summary = connect::add_notes(&notes, &config.anki)
             .context("Failed to send notes to AnkiConnect")?;
-        writeln!(io::stderr(), "Imported {count} notes")?;
+        writeln!(
+            io::stderr(),
+            "Imported {} new notes, updated {}, {} unchanged",
+            summary.created,
+            summary.updated,
+            summary.unchanged
+        )?;
+    } else if let Some(dir) = args.markdown {
+        let count =
+            markdown::write_markdown(&notes, &dir).context("Failed to write notes to Markdown")?;
+        writeln!(io::stderr(), "Exported {count} notes")?;
+    } else if let Some(path) = args.apkg {
+        // Built in memory first, so that a failure leaves no truncated package
+        let mut package = Cursor::new(Vec::new());
+        let count = apkg::write_apkg(&notes, &config.anki, &mut package)
+            .context("Failed to write notes to the Anki package")?;
+        fs::write(&path, package.into_inner())
+            .with_context(|| format!("Failed to write output file: {}", path.display()))?;
+        writeln!(io::stderr(), "Exported {count} notes")?;
     } else {
-        let count = match args.output {
-            Some(path) => {
-                let file = File::create(&path)
-                    .with_context(|| format!("Failed to create output file: {}", path.display()))?;
-                csv_writer::write_csv(&notes, file).context("Failed to write notes to CSV")?
-            }
-            None => csv_writer::write_csv(&notes, io::stdout())
-                .context("Failed to write notes to CSV")?,
-        };
+        let count = write_output(args.format, args.output, &notes, &config)?;
         writeln!(io::stderr(), "Exported {count} notes")?;
     }
+    if let Some(ledger) = &mut ledger {
+        ledger.record(&target, &notes);
+        ledger.save()?;
+    }
     Ok(())
 }
+
+/// Writes the notes in `format` to the `output` file, or to stdout
+fn write_output(
+    format: OutputFormat,
+    output: Option<PathBuf>,
+    notes: &[note::Note],
+    config: &AppConfig,
+) -> Result<usize> {
+    let writer: Box<dyn Write> = match output {
+        Some(path) => Box::new(
+            File::create(&path)
+                .with_context(|| format!("Failed to create output file: {}", path.display()))?,
+        ),
+        None => Box::new(io::stdout()),
+    };
+    Ok(match format {
+        OutputFormat::Csv => csv_writer::write_csv(notes, &config.csv, &config.anki, writer)
+            .context("Failed to write notes to CSV")?,
+        OutputFormat::Json => json_writer::write_json(notes, BufWriter::new(writer))
+            .context("Failed to write notes to JSON")?,
+        OutputFormat::Jsonl => json_writer::write_jsonl(notes, BufWriter::new(writer))
+            .context("Failed to write notes to JSON Lines")?,
+    })
+}
@@ -0,0 +1,345 @@
+use crate::note::Note;
+use anyhow::{Context, Result, bail};
+use indexmap::IndexMap;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// Markers of the section kept as is when a book file is written again
+const USER_START: &str = "<!-- kindlenotes2anki:user -->";
+const USER_END: &str = "<!-- kindlenotes2anki:end-user -->";
+const DEFAULT_USER_SECTION: &str = "## My notes\n";
+
+/// Writes one Markdown file per book into `dir`: YAML front matter, then
+/// a section left to the user, then the highlights as blockquotes. The
+/// user section of an existing file is carried over, and a file without
+/// its markers is left alone with an error rather than overwritten. Every
+/// file is checked before the first write, so that an error leaves the
+/// directory as it was, and books whose file names only differ by case are
+/// refused as well.
+pub fn write_markdown(notes: &[Note], dir: &Path) -> Result<usize> {
+    let mut books: IndexMap<(&str, Option<&str>), Vec<&Note>> = IndexMap::new();
+    for note in notes {
+        books
+            .entry((note.title.as_str(), note.author.as_deref()))
+            .or_default()
+            .push(note);
+    }
+    // Case-insensitive file systems would mix up `Dune.md` and `DUNE.md`
+    let mut file_names: HashMap<String, (&str, Option<&str>)> = HashMap::new();
+    for &(title, author) in books.keys() {
+        if let Some((other_title, other_author)) =
+            file_names.insert(file_name(title, author).to_lowercase(), (title, author))
+        {
+            bail!(
+                "{} and {} would be written to the same file",
+                book_name(other_title, other_author),
+                book_name(title, author)
+            );
+        }
+    }
+    let mut files = Vec::with_capacity(books.len());
+    for ((title, author), mut book) in books {
+        let path = dir.join(file_name(title, author));
+        let user_section = match fs::read_to_string(&path) {
+            Ok(contents) => user_section(&contents).with_context(|| {
+                format!(
+                    "{} has no {USER_START} section, move it away to export this book",
+                    path.display()
+                )
+            })?,
+            Err(err) if err.kind() == ErrorKind::NotFound => DEFAULT_USER_SECTION.to_owned(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to read {}", path.display()));
+            }
+        };
+        // Reading order; clippings without a location come last
+        book.sort_by_key(|note| note.location.map_or(u32::MAX, |location| location.start));
+        files.push((path, render_book(title, author, &book, &user_section)));
+    }
+    fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create output directory: {}", dir.display()))?;
+    for (path, contents) in files {
+        fs::write(&path, contents)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+    Ok(notes.len())
+}
+
+/// File name of a book, which only depends on its title and author so that
+/// every run updates the same file: `Title (Author).md`, or `Title.md`
+fn file_name(title: &str, author: Option<&str>) -> String {
+    format!("{}.md", file_stem(&book_name(title, author)))
+}
+
+fn book_name(title: &str, author: Option<&str>) -> String {
+    match author {
+        Some(author) => format!("{title} ({author})"),
+        None => title.to_owned(),
+    }
+}
+
+/// Text between the user section markers
+fn user_section(contents: &str) -> Option<String> {
+    let start = contents.find(USER_START)? + USER_START.len();
+    let end = start + contents[start..].find(USER_END)?;
+    Some(contents[start..end].trim_matches('\n').to_owned() + "\n")
+}
+
+fn render_book(title: &str, author: Option<&str>, notes: &[&Note], user_section: &str) -> String {
+    let mut markdown = String::new();
+    markdown.push_str("---\n");
+    let _ = writeln!(markdown, "title: {}", yaml_string(title));
+    if let Some(author) = author {
+        let _ = writeln!(markdown, "author: {}", yaml_string(author));
+    }
+    let _ = writeln!(markdown, "highlights: {}", notes.len());
+    if let Some(last) = notes.iter().filter_map(|note| note.added_at).max() {
+        let _ = writeln!(
+            markdown,
+            "last_highlight: {}",
+            last.format("%Y-%m-%d %H:%M")
+        );
+    }
+    markdown.push_str("---\n\n");
+    let _ = writeln!(markdown, "# {title}\n");
+    let _ = writeln!(markdown, "{USER_START}\n{user_section}{USER_END}\n");
+    markdown.push_str("## Highlights\n");
+    for note in notes {
+        markdown.push('\n');
+        for line in note.tidied_note.lines() {
+            markdown.push('>');
+            let line = escape_line(line);
+            if !line.is_empty() {
+                markdown.push(' ');
+                markdown.push_str(&line);
+            }
+            markdown.push('\n');
+        }
+        let meta: Vec<String> = [
+            note.location.map(|location| format!("Location {location}")),
+            note.page.map(|page| format!("page {page}")),
+            note.date(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        if !meta.is_empty() {
+            let _ = writeln!(markdown, "\n*{}*", meta.join(", "));
+        }
+        if let Some(comment) = &note.comment {
+            let comment: Vec<String> = comment.lines().map(escape_line).collect();
+            let _ = writeln!(markdown, "\n**Note:** {}", comment.join("\n"));
+        }
+    }
+    markdown
+}
+
+/// Line of clipping text, without the indentation and with the marker
+/// starting a Markdown block (heading, list, quote, fence, table, HTML...)
+/// escaped, so that it stays a line of text
+fn escape_line(line: &str) -> String {
+    let line = line.trim_start();
+    let digits = line.len() - line.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    match line[digits..].chars().next() {
+        // Ordered list item: "1. " or "1) "
+        Some('.' | ')') if digits > 0 => format!("{}\\{}", &line[..digits], &line[digits..]),
+        Some('#' | '-' | '+' | '*' | '_' | '=' | '>' | '<' | '`' | '~' | '|') if digits == 0 => {
+            format!("\\{line}")
+        }
+        _ => line.to_owned(),
+    }
+}
+
+/// Double-quoted YAML scalar
+fn yaml_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// File name of a book, without the characters that are invalid on
+/// Windows or would change the directory
+fn file_stem(title: &str) -> String {
+    let stem: String = title
+        .chars()
+        .map(|c| {
+            if c.is_control() || r#"/\:*?"<>|"#.contains(c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    let stem = stem.trim().trim_end_matches('.');
+    if stem.is_empty() {
+        "Untitled".to_owned()
+    } else {
+        stem.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::note::fixtures::dune;
+    use tempfile::TempDir;
+
+    #[test]
+    fn writes_front_matter_and_highlights_in_location_order() {
+        let dir = TempDir::new().unwrap();
+        let mut later = dune(200, "The spice must flow.");
+        later.page = Some(40);
+        later.added_at =
+            chrono::NaiveDate::from_ymd_opt(2024, 3, 1).and_then(|date| date.and_hms_opt(9, 30, 0));
+        let mut first = dune(100, "Fear is the mind-killer.\n\nFear is the little-death.");
+        first.comment = Some("Litany".to_owned());
+        first.added_at =
+            chrono::NaiveDate::from_ymd_opt(2024, 1, 1).and_then(|date| date.and_hms_opt(10, 0, 0));
+
+        assert_eq!(write_markdown(&[later, first], dir.path()).unwrap(), 2);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("Dune (Herbert, Frank).md")).unwrap(),
+            "---
+title: \"Dune\"
+author: \"Herbert, Frank\"
+highlights: 2
+last_highlight: 2024-03-01 09:30
+---
+
+# Dune
+
+<!-- kindlenotes2anki:user -->
+## My notes
+<!-- kindlenotes2anki:end-user -->
+
+## Highlights
+
+> Fear is the mind-killer.
+>
+> Fear is the little-death.
+
+*Location 100-102, 2024-01-01 10:00*
+
+**Note:** Litany
+
+> The spice must flow.
+
+*Location 200-202, page 40, 2024-03-01 09:30*
+"
+        );
+    }
+
+    #[test]
+    fn keeps_user_section_when_updating() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("Dune (Herbert, Frank).md");
+        write_markdown(&[dune(100, "Fear is the mind-killer.")], dir.path()).unwrap();
+        let edited = fs::read_to_string(&path)
+            .unwrap()
+            .replace("## My notes\n", "## My notes\n\nRead in 2024.\n");
+        fs::write(&path, edited).unwrap();
+
+        let notes = [
+            dune(100, "Fear is the mind-killer."),
+            dune(200, "The spice must flow."),
+        ];
+        write_markdown(&notes, dir.path()).unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.contains(&format!(
+            "{USER_START}\n## My notes\n\nRead in 2024.\n{USER_END}\n"
+        )));
+        assert!(contents.contains("highlights: 2\n"));
+        assert!(contents.contains("> The spice must flow.\n"));
+    }
+
+    #[test]
+    fn refuses_to_overwrite_file_without_markers() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("Dune (Herbert, Frank).md");
+        fs::write(&path, "My own notes").unwrap();
+        let err = write_markdown(&[dune(100, "Fear")], dir.path()).unwrap_err();
+        assert!(err.to_string().contains("move it away"), "{err}");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "My own notes");
+    }
+
+    #[test]
+    fn file_names_are_sanitized_and_distinct() {
+        assert_eq!(file_stem("Why? A/B: \"Test\"..."), "Why_ A_B_ _Test_");
+        assert_eq!(file_stem(" . "), "Untitled");
+
+        let dir = TempDir::new().unwrap();
+        let mut other = dune(1, "Other author");
+        other.author = Some("Someone".to_owned());
+        let mut anonymous = dune(1, "No author");
+        anonymous.author = None;
+        write_markdown(&[anonymous, dune(1, "Fear"), other], dir.path()).unwrap();
+        let mut names: Vec<String> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            ["Dune (Herbert, Frank).md", "Dune (Someone).md", "Dune.md"]
+        );
+        let anonymous = fs::read_to_string(dir.path().join("Dune.md")).unwrap();
+        assert!(anonymous.contains("> No author\n"));
+        assert!(!anonymous.contains("author:"));
+    }
+
+    #[test]
+    fn refuses_file_names_differing_by_case() {
+        let dir = TempDir::new().unwrap();
+        let mut shouting = dune(1, "FEAR");
+        shouting.title = "DUNE".to_owned();
+        shouting.author = Some("HERBERT, FRANK".to_owned());
+        let err = write_markdown(&[dune(1, "Fear"), shouting], dir.path()).unwrap_err();
+        assert!(err.to_string().contains("same file"), "{err}");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn leaves_the_directory_as_is_when_a_file_is_refused() {
+        let dir = TempDir::new().unwrap();
+        let mut hobbit = dune(1, "In a hole in the ground");
+        hobbit.title = "The Hobbit".to_owned();
+        let path = dir.path().join("The Hobbit (Herbert, Frank).md");
+        fs::write(&path, "My own notes").unwrap();
+        write_markdown(&[dune(100, "Fear"), hobbit], dir.path()).unwrap_err();
+        assert!(!dir.path().join("Dune (Herbert, Frank).md").exists());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "My own notes");
+    }
+
+    #[test]
+    fn escapes_markdown_block_markers() {
+        assert_eq!(escape_line("# Chapter"), "\\# Chapter");
+        assert_eq!(escape_line("    - item"), "\\- item");
+        assert_eq!(escape_line("12. Point"), "12\\. Point");
+        assert_eq!(escape_line("```"), "\\```");
+        assert_eq!(escape_line("1984 was a year"), "1984 was a year");
+        assert_eq!(escape_line("Plain - text"), "Plain - text");
+
+        let dir = TempDir::new().unwrap();
+        let mut list = dune(100, "Rules:\n- one\n# two");
+        list.comment = Some("> not a quote".to_owned());
+        write_markdown(&[list], dir.path()).unwrap();
+        let contents = fs::read_to_string(dir.path().join("Dune (Herbert, Frank).md")).unwrap();
+        assert!(
+            contents.contains("> Rules:\n> \\- one\n> \\# two\n"),
+            "{contents}"
+        );
+        assert!(
+            contents.contains("**Note:** \\> not a quote\n"),
+            "{contents}"
+        );
+    }
+
+    #[test]
+    fn escapes_front_matter_strings() {
+        assert_eq!(
+            yaml_string(r#"A "quoted" \ title"#),
+            r#""A \"quoted\" \\ title""#
+        );
+    }
+}
@@ -1,8 +1,11 @@
 use crate::app_config::{AppConfig, ParserConfig};
-use crate::note::Note;
+use crate::locale::{self, Locale};
+use crate::note::{ClippingKind, Location, Note};
 use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{self, BufRead, BufReader, Write};
 use std::path::Path;
 
 const SEPARATOR: &str = "==========";
@@ -14,6 +17,8 @@ pub fn parse_clippings(filename: &Path, config: &AppConfig) -> Result<Vec<Note>>
     let mut notes = Vec::with_capacity(100);
     let mut current_note = Vec::with_capacity(10);
     let prefixes = &config.parser;
+    let presets = detection_presets(prefixes);
+    let mut entry_number = 0;
 
     for line in reader.lines() {
         let mut line = line?;
@@ -27,7 +32,10 @@ pub fn parse_clippings(filename: &Path, config: &AppConfig) -> Result<Vec<Note>>
         }
         if line.starts_with(SEPARATOR) {
             if !current_note.is_empty() {
+                entry_number += 1;
+                let prefixes = detect_prefixes(&current_note, &presets).unwrap_or(prefixes);
                 if let Some(note) = parse_note(&current_note, prefixes) {
+                    warn_on_unparsed_timestamp(&note, entry_number)?;
                     notes.push(note);
                 }
                 current_note.clear();
@@ -38,12 +46,277 @@ pub fn parse_clippings(filename: &Path, config: &AppConfig) -> Result<Vec<Note>>
     }
 
     if !current_note.is_empty()
-        && let Some(note) = parse_note(&current_note, prefixes)
+        && let Some(note) = parse_note(
+            &current_note,
+            detect_prefixes(&current_note, &presets).unwrap_or(prefixes),
+        )
     {
+        warn_on_unparsed_timestamp(&note, entry_number + 1)?;
         notes.push(note);
     }
 
-    Ok(notes)
+    Ok(attach_comments(notes))
+}
+
+fn warn_on_unparsed_timestamp(note: &Note, entry_number: usize) -> Result<()> {
+    if let (Some(added_on), None) = (&note.added_on, note.added_at) {
+        writeln!(
+            io::stderr(),
+            "Warning: entry {entry_number}: could not parse date \"{added_on}\""
+        )?;
+    }
+    Ok(())
+}
+
+/// Parser presets of every supported Kindle language, also honouring the
+/// configured ignored patterns
+fn detection_presets(prefixes: &ParserConfig) -> Vec<ParserConfig> {
+    Locale::value_variants()
+        .iter()
+        .map(|locale| {
+            let mut preset = locale.parser_config();
+            for pattern in &prefixes.ignored {
+                if !preset.ignored.contains(pattern) {
+                    preset.ignored.push(pattern.clone());
+                }
+            }
+            preset
+        })
+        .collect()
+}
+
+/// Finds the preset whose markers match the metadata line of an entry, so
+/// a single file may mix entries written by Kindles in different languages
+fn detect_prefixes<'a>(lines: &[String], presets: &'a [ParserConfig]) -> Option<&'a ParserConfig> {
+    let metadata_line = lines.get(1)?;
+    presets
+        .iter()
+        .find(|preset| parse_metadata(metadata_line, preset).is_some())
+}
+
+/// Merges each "- Your Note" entry into the highlight whose location range
+/// contains it, preferring the closest one in the file. Notes that annotate
+/// no known highlight are kept as cards of their own.
+fn attach_comments(notes: Vec<Note>) -> Vec<Note> {
+    let books = located_highlights_by_book(&notes);
+    let mut attached_to = vec![None; notes.len()];
+    for (index, note) in notes.iter().enumerate() {
+        if note.kind != ClippingKind::Note {
+            continue;
+        }
+        let Some(location) = note.location else {
+            continue;
+        };
+        let Some(book) = books.get(&book_key(note)) else {
+            continue;
+        };
+        // Highlights starting after the note cannot contain it, and those
+        // starting more than the longest range before it neither
+        let starts_before = book
+            .highlights
+            .partition_point(|&candidate| start(&notes[candidate]) <= location.start);
+        attached_to[index] = book.highlights[..starts_before]
+            .iter()
+            .rev()
+            .take_while(|&&candidate| {
+                start(&notes[candidate]).saturating_add(book.longest_range) >= location.start
+            })
+            .filter(|&&candidate| {
+                notes[candidate]
+                    .location
+                    .is_some_and(|range| location.start <= range.end)
+            })
+            .min_by_key(|&&candidate| (candidate.abs_diff(index), candidate))
+            .copied();
+    }
+
+    let mut comments: Vec<Option<String>> = vec![None; notes.len()];
+    for (index, target) in attached_to.iter().enumerate() {
+        if let Some(target) = *target {
+            let text = &notes[index].tidied_note;
+            match &mut comments[target] {
+                Some(comment) => {
+                    comment.push('\n');
+                    comment.push_str(text);
+                }
+                comment @ None => *comment = Some(text.clone()),
+            }
+        }
+    }
+
+    notes
+        .into_iter()
+        .zip(attached_to)
+        .zip(comments)
+        .filter_map(|((mut note, target), comment)| {
+            if target.is_some() {
+                return None;
+            }
+            if comment.is_some() {
+                note.comment = comment;
+            }
+            Some(note)
+        })
+        .collect()
+}
+
+/// Book of a note, by title and author
+type BookKey<'a> = (&'a str, Option<&'a str>);
+
+fn book_key(note: &Note) -> BookKey<'_> {
+    (&note.title, note.author.as_deref())
+}
+
+/// Start of the location range of a note known to have one
+fn start(note: &Note) -> u32 {
+    note.location.map_or(0, |location| location.start)
+}
+
+/// Highlights of a book that have a location
+#[derive(Default)]
+struct BookHighlights {
+    /// Indices of the highlights, by location start
+    highlights: Vec<usize>,
+    /// Length of the longest location range
+    longest_range: u32,
+}
+
+fn located_highlights_by_book(notes: &[Note]) -> HashMap<BookKey<'_>, BookHighlights> {
+    let mut books: HashMap<BookKey, BookHighlights> = HashMap::new();
+    for (index, note) in notes.iter().enumerate() {
+        if note.kind != ClippingKind::Highlight {
+            continue;
+        }
+        if let Some(location) = note.location {
+            let book = books.entry(book_key(note)).or_default();
+            book.highlights.push(index);
+            book.longest_range = book
+                .longest_range
+                .max(location.end.saturating_sub(location.start));
+        }
+    }
+    for book in books.values_mut() {
+        book.highlights.sort_by_key(|&index| start(&notes[index]));
+    }
+    books
+}
+
+/// Collapses highlights that were extended or adjusted on the device into
+/// their final version. Kindle keeps the old entry and appends the new one,
+/// so a highlight is dropped when a later highlight of the same book overlaps
+/// its location range, or touches it and contains its text (or the reverse).
+/// A personal note attached to a dropped highlight is carried over to the
+/// kept one.
+pub fn dedupe_highlights(notes: Vec<Note>) -> Vec<Note> {
+    let mut superseded_by: Vec<Option<usize>> = vec![None; notes.len()];
+    let mut supersede = |a: usize, b: usize| {
+        let (earlier, later) = (a.min(b), a.max(b));
+        if superseded_by[earlier].is_none_or(|current| later < current) {
+            superseded_by[earlier] = Some(later);
+        }
+    };
+    // Located highlights are sorted by start, so only the following ones
+    // starting before the end of a range can overlap or touch it
+    for book in located_highlights_by_book(&notes).into_values() {
+        for (position, &a) in book.highlights.iter().enumerate() {
+            let end = notes[a].location.map_or(0, |location| location.end);
+            for &b in book.highlights[position + 1..]
+                .iter()
+                .take_while(|&&b| start(&notes[b]) <= end.saturating_add(1))
+            {
+                if is_same_highlight(&notes[a], &notes[b]) {
+                    supersede(a, b);
+                }
+            }
+        }
+    }
+    // Highlights without location can only be compared by text
+    let mut unlocated: HashMap<BookKey, Vec<usize>> = HashMap::new();
+    for (index, note) in notes.iter().enumerate() {
+        if note.kind == ClippingKind::Highlight && note.location.is_none() {
+            unlocated.entry(book_key(note)).or_default().push(index);
+        }
+    }
+    for book in unlocated.into_values() {
+        for (position, &a) in book.iter().enumerate() {
+            for &b in &book[position + 1..] {
+                if is_same_highlight(&notes[a], &notes[b]) {
+                    supersede(a, b);
+                }
+            }
+        }
+    }
+
+    // Follow chains of edits to the final version to carry comments over
+    let mut inherited: Vec<Option<String>> = vec![None; notes.len()];
+    for (index, note) in notes.iter().enumerate() {
+        let Some(comment) = &note.comment else {
+            continue;
+        };
+        let mut target = index;
+        while let Some(next) = superseded_by[target] {
+            target = next;
+        }
+        if target != index && inherited[target].is_none() {
+            inherited[target] = Some(comment.clone());
+        }
+    }
+
+    notes
+        .into_iter()
+        .zip(superseded_by)
+        .zip(inherited)
+        .filter_map(|((mut note, superseded), comment)| {
+            if superseded.is_some() {
+                return None;
+            }
+            if note.comment.is_none() {
+                note.comment = comment;
+            }
+            Some(note)
+        })
+        .collect()
+}
+
+/// Flags the clippings starting at the same location of a book as another
+/// one, so that their stable ids differ. Run on every clipping of the file,
+/// so that an id does not depend on which clippings are exported together.
+pub fn mark_shared_starts(notes: &mut [Note]) {
+    let mut counts: HashMap<(BookKey, u32), usize> = HashMap::new();
+    for note in notes.iter() {
+        if let Some(location) = note.location {
+            *counts.entry((book_key(note), location.start)).or_default() += 1;
+        }
+    }
+    let shared: Vec<bool> = notes
+        .iter()
+        .map(|note| {
+            note.location
+                .is_some_and(|location| counts[&(book_key(note), location.start)] > 1)
+        })
+        .collect();
+    for (note, shared) in notes.iter_mut().zip(shared) {
+        note.shared_start = shared;
+    }
+}
+
+fn is_same_highlight(first: &Note, second: &Note) -> bool {
+    let containing = || {
+        first.tidied_note.contains(&second.tidied_note)
+            || second.tidied_note.contains(&first.tidied_note)
+    };
+    match (first.location, second.location) {
+        (Some(a), Some(b)) => {
+            // Distinct highlights often share a boundary location, so ranges
+            // merely touching at an end only match when one text contains
+            // the other, e.g. a highlight extended past its end
+            let overlapping = a == b || (a.start < b.end && b.start < a.end);
+            let touching = a.start <= b.end.saturating_add(1) && b.start <= a.end.saturating_add(1);
+            overlapping || (touching && containing())
+        }
+        (None, None) => containing(),
+        _ => false,
+    }
 }
 
 fn parse_note(lines: &[String], prefixes: &ParserConfig) -> Option<Note> {
@@ -51,13 +324,26 @@ fn parse_note(lines: &[String], prefixes: &ParserConfig) -> Option<Note> {
         return None;
     }
 
-    let title = lines[0].trim();
-    if is_empty_or_useless_line(title, prefixes) {
+    let raw_title = lines[0].trim();
+    if is_empty_or_useless_line(raw_title, prefixes)
+        || parse_metadata(raw_title, prefixes).is_some()
+    {
         return None;
     }
+    let (title, author) = split_title_author(raw_title);
+
+    // The metadata line follows the title; later lines are the clipping,
+    // which may itself contain a kind marker, as in a bullet list
+    let metadata = lines.get(1).and_then(|line| parse_metadata(line, prefixes));
+    let body = if metadata.is_some() {
+        &lines[2..]
+    } else {
+        &lines[1..]
+    };
+    let metadata = metadata.unwrap_or_default();
 
     let mut tidied_note = String::new();
-    for line in &lines[1..] {
+    for line in body {
         if is_empty_or_useless_line(line, prefixes) {
             continue;
         }
@@ -84,22 +370,168 @@ fn parse_note(lines: &[String], prefixes: &ParserConfig) -> Option<Note> {
     } else {
         Some(Note {
             title: title.to_owned(),
+            author,
             tidied_note: tidied_note.to_owned(),
+            comment: None,
+            kind: metadata.kind,
+            page: metadata.page,
+            location: metadata.location,
+            added_at: metadata
+                .added_on
+                .as_deref()
+                .and_then(locale::parse_timestamp),
+            added_on: metadata.added_on,
+            shared_start: false,
         })
     }
 }
 
+/// Splits "Example Book (Vol. 1) (Author, A; Other, B)" on its trailing
+/// parenthesised group, honouring nested parentheses
+fn split_title_author(raw_title: &str) -> (&str, Option<String>) {
+    if !raw_title.ends_with(')') {
+        return (raw_title, None);
+    }
+    let mut depth = 0_usize;
+    for (pos, c) in raw_title.char_indices().rev() {
+        match c {
+            ')' => depth += 1,
+            '(' => {
+                depth -= 1;
+                if depth == 0 {
+                    let title = raw_title[..pos].trim_end();
+                    let inner = &raw_title[pos + 1..raw_title.len() - 1];
+                    let authors: Vec<&str> = inner
+                        .split(';')
+                        .map(str::trim)
+                        .filter(|author| !author.is_empty())
+                        .collect();
+                    if title.is_empty() || authors.is_empty() {
+                        return (raw_title, None);
+                    }
+                    return (title, Some(authors.join("; ")));
+                }
+            }
+            _ => {}
+        }
+    }
+    // Unbalanced parentheses: keep the title verbatim
+    (raw_title, None)
+}
+
+/// Structured content of a "- Your Highlight on page 10 | location 100-101 | Added on ..." line
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Metadata {
+    kind: ClippingKind,
+    page: Option<u32>,
+    location: Option<Location>,
+    added_on: Option<String>,
+}
+
+fn parse_metadata(line: &str, prefixes: &ParserConfig) -> Option<Metadata> {
+    let kind = if is_marked(line, &prefixes.highlight) {
+        ClippingKind::Highlight
+    } else if is_marked(line, &prefixes.note) {
+        ClippingKind::Note
+    } else if is_marked(line, &prefixes.bookmark) {
+        ClippingKind::Bookmark
+    } else {
+        return None;
+    };
+
+    let mut metadata = Metadata {
+        kind,
+        ..Metadata::default()
+    };
+    let segments: Vec<&str> = line.split('|').map(str::trim).collect();
+    for (index, segment) in segments.iter().enumerate() {
+        // Chinese puts page and location in the same segment
+        let location = find_number(segment, &prefixes.location);
+        let page = find_number(segment, &prefixes.page);
+        if let Some((start, end)) = location {
+            metadata.location = Some(Location { start, end });
+        }
+        if let Some((page, _)) = page {
+            metadata.page = Some(page);
+        }
+        if location.is_none() && page.is_none() && index > 0 && index == segments.len() - 1 {
+            // The timestamp always comes last, after the page/location parts
+            metadata.added_on = Some((*segment).to_owned());
+        }
+    }
+    Some(metadata)
+}
+
+/// Kindle markers are prefixes in most languages, but Japanese and Chinese
+/// announce the clipping kind at the end of the first segment of the
+/// metadata line instead
+fn is_marked(line: &str, marker: &str) -> bool {
+    line.starts_with(marker) || (line.starts_with("- ") && line.contains(marker))
+}
+
+/// Maximum number of characters between a keyword and its number
+const MAX_KEYWORD_GAP: usize = 3;
+
+/// Finds the number announced by any of `keywords` (case-insensitive) in
+/// `segment`: "page 10", "位置No. 100-101" and "10ページ" are all supported
+fn find_number(segment: &str, keywords: &[String]) -> Option<(u32, u32)> {
+    let lowered = segment.to_lowercase();
+    keywords.iter().find_map(|keyword| {
+        let keyword = keyword.to_lowercase();
+        let pos = lowered.find(&keyword)?;
+        let after = &lowered[pos + keyword.len()..];
+        if let Some(digit) = after.find(|c: char| c.is_ascii_digit())
+            && after[..digit].chars().count() <= MAX_KEYWORD_GAP
+        {
+            return parse_range(&after[digit..]);
+        }
+        let before = &lowered[..pos];
+        let last_digit = before.rfind(|c: char| c.is_ascii_digit())?;
+        if before[last_digit + 1..].chars().count() > MAX_KEYWORD_GAP {
+            return None;
+        }
+        let start = before[..last_digit]
+            .trim_end_matches(|c: char| c.is_ascii_digit())
+            .len();
+        let number = before[start..=last_digit].parse().ok()?;
+        Some((number, number))
+    })
+}
+
+/// Parses the first "N" or "N-M" number range found in `text`
+fn parse_range(text: &str) -> Option<(u32, u32)> {
+    let start_pos = text.find(|c: char| c.is_ascii_digit())?;
+    let rest = &text[start_pos..];
+    let start_len = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    let start = rest[..start_len].parse().ok()?;
+    let end = rest[start_len..]
+        .strip_prefix('-')
+        .and_then(|tail| {
+            let end_len = tail
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(tail.len());
+            tail[..end_len].parse().ok()
+        })
+        .unwrap_or(start);
+    Some((start, end))
+}
+
+/// Empty lines, and stray metadata lines in the languages whose markers
+/// are prefixes
 fn is_empty_or_useless_line(line: &str, prefixes: &ParserConfig) -> bool {
     line.is_empty()
-        || line.starts_with(&prefixes.highlight)
-        || line.starts_with(&prefixes.bookmark)
-        || line.starts_with(&prefixes.note)
+        || [&prefixes.highlight, &prefixes.bookmark, &prefixes.note]
+            .into_iter()
+            .any(|marker| line.starts_with(marker.as_str()))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::app_config::{AppConfig, ParserConfig};
+    use crate::locale::Locale;
     use std::io::Write;
     use tempfile::NamedTempFile;
 
@@ -180,8 +612,179 @@ mod tests {
         ];
 
         let read_note = parse_note(&fake_note, &french_config().parser).unwrap();
-        assert_eq!(read_note.title, "A fake title (Last, First)");
+        assert_eq!(read_note.title, "A fake title");
+        assert_eq!(read_note.author.as_deref(), Some("Last, First"));
         assert_eq!(read_note.tidied_note, "This is a fake highlight.");
+        assert_eq!(read_note.kind, ClippingKind::Highlight);
+        assert_eq!(read_note.page, None);
+        assert_eq!(
+            read_note.location,
+            Some(Location {
+                start: 3592,
+                end: 3592
+            })
+        );
+        assert_eq!(
+            read_note.added_on.as_deref(),
+            Some("Ajouté le mardi 6 novembre 2018 à 08:50:39")
+        );
+        assert_eq!(
+            read_note.added_at.map(|at| at.to_string()),
+            Some("2018-11-06 08:50:39".to_owned())
+        );
+    }
+
+    #[test]
+    fn parses_english_metadata_with_page() {
+        let metadata = parse_metadata(
+            "- Your Highlight on page 10 | location 100-101 | Added on Monday, January 1, 2020 10:00:00 AM",
+            &english_config().parser,
+        )
+        .unwrap();
+        assert_eq!(
+            metadata,
+            Metadata {
+                kind: ClippingKind::Highlight,
+                page: Some(10),
+                location: Some(Location {
+                    start: 100,
+                    end: 101
+                }),
+                added_on: Some("Added on Monday, January 1, 2020 10:00:00 AM".to_owned()),
+            }
+        );
+    }
+
+    #[test]
+    fn parses_note_metadata_with_single_location() {
+        let metadata = parse_metadata(
+            "- Votre note sur la page 7 | emplacement 3752 | Ajoutée le vendredi 16 novembre 2018 à 13:51:19",
+            &french_config().parser,
+        )
+        .unwrap();
+        assert_eq!(metadata.kind, ClippingKind::Note);
+        assert_eq!(metadata.page, Some(7));
+        assert_eq!(
+            metadata.location,
+            Some(Location {
+                start: 3752,
+                end: 3752
+            })
+        );
+    }
+
+    #[test]
+    fn splits_title_and_author() {
+        assert_eq!(
+            split_title_author("Example Book (Author, A)"),
+            ("Example Book", Some("Author, A".to_owned()))
+        );
+    }
+
+    #[test]
+    fn splits_title_with_nested_parentheses() {
+        assert_eq!(
+            split_title_author("Example (Book (Vol. 1)) (Author (Ed.))"),
+            ("Example (Book (Vol. 1))", Some("Author (Ed.)".to_owned()))
+        );
+    }
+
+    #[test]
+    fn splits_multiple_authors() {
+        assert_eq!(
+            split_title_author("Example Book (Author, A;Other, B ; )"),
+            ("Example Book", Some("Author, A; Other, B".to_owned()))
+        );
+    }
+
+    #[test]
+    fn keeps_title_without_author() {
+        assert_eq!(split_title_author("Example Book"), ("Example Book", None));
+        assert_eq!(split_title_author("(Only parens)"), ("(Only parens)", None));
+        assert_eq!(split_title_author("Broken )"), ("Broken )", None));
+    }
+
+    #[test]
+    fn parses_metadata_for_every_locale() {
+        let lines = [
+            (
+                Locale::En,
+                "- Your Highlight on page 10 | location 100-101 | Added on Monday, January 1, 2020 10:00:00 AM",
+            ),
+            (
+                Locale::Fr,
+                "- Votre surlignement sur la page 10 | emplacement 100-101 | Ajouté le lundi 1 janvier 2020 10:00:00",
+            ),
+            (
+                Locale::De,
+                "- Ihre Markierung auf Seite 10 | Position 100-101 | Hinzugefügt am Montag, 1. Januar 2020 10:00:00",
+            ),
+            (
+                Locale::Es,
+                "- Tu subrayado en la página 10 | posición 100-101 | Añadido el lunes, 1 de enero de 2020 10:00:00",
+            ),
+            (
+                Locale::It,
+                "- La tua evidenziazione a pagina 10 | posizione 100-101 | Aggiunto in data lunedì 1 gennaio 2020 10:00:00",
+            ),
+            (
+                Locale::Pt,
+                "- Seu destaque na página 10 | posição 100-101 | Adicionado: segunda-feira, 1 de janeiro de 2020 10:00:00",
+            ),
+            (
+                Locale::Nl,
+                "- Je markering op pagina 10 | locatie 100-101 | Toegevoegd op maandag 1 januari 2020 10:00:00",
+            ),
+            (
+                Locale::Ja,
+                "- 10ページ|位置No. 100-101のハイライト |作成日: 2020年1月1日 水曜日 10:00:00",
+            ),
+            (
+                Locale::Zh,
+                "- 您在第 10 页（位置 #100-101）的标注 | 添加于 2020年1月1日星期三 上午10:00:00",
+            ),
+        ];
+        for (locale, line) in lines {
+            let metadata = parse_metadata(line, &locale.parser_config())
+                .unwrap_or_else(|| panic!("{locale:?} line not recognised"));
+            assert_eq!(metadata.kind, ClippingKind::Highlight, "{locale:?}");
+            assert_eq!(metadata.page, Some(10), "{locale:?}");
+            assert_eq!(
+                metadata.location,
+                Some(Location {
+                    start: 100,
+                    end: 101
+                }),
+                "{locale:?}"
+            );
+            assert!(metadata.added_on.is_some(), "{locale:?}");
+        }
+    }
+
+    #[test]
+    fn parses_japanese_note_without_page() {
+        let metadata = parse_metadata(
+            "- 位置No. 250のメモ |作成日: 2020年1月1日 水曜日 10:00:00",
+            &Locale::Ja.parser_config(),
+        )
+        .unwrap();
+        assert_eq!(metadata.kind, ClippingKind::Note);
+        assert_eq!(metadata.page, None);
+        assert_eq!(
+            metadata.location,
+            Some(Location {
+                start: 250,
+                end: 250
+            })
+        );
+    }
+
+    #[test]
+    fn non_metadata_line_is_not_parsed() {
+        assert_eq!(
+            parse_metadata("A standard fake highlight", &french_config().parser),
+            None
+        );
     }
 
     #[test]
@@ -190,9 +793,9 @@ mod tests {
         let file = write_temp(content);
         let notes = parse_clippings(file.path(), &french_config()).unwrap();
         assert_eq!(notes.len(), 2);
-        assert_eq!(notes[0].title, "Livre Exemple (Auteur, A)");
+        assert_eq!(notes[0].title, "Livre Exemple");
         assert_eq!(notes[0].tidied_note, "Premier surlignement.");
-        assert_eq!(notes[1].title, "Livre Exemple (Auteur, A)");
+        assert_eq!(notes[1].title, "Livre Exemple");
         assert_eq!(notes[1].tidied_note, "Deuxième surlignement.");
     }
 
@@ -202,9 +805,9 @@ mod tests {
         let file = write_temp(content);
         let notes = parse_clippings(file.path(), &english_config()).unwrap();
         assert_eq!(notes.len(), 2);
-        assert_eq!(notes[0].title, "Example Book (Author, A)");
+        assert_eq!(notes[0].title, "Example Book");
         assert_eq!(notes[0].tidied_note, "First highlight.");
-        assert_eq!(notes[1].title, "Example Book (Author, A)");
+        assert_eq!(notes[1].title, "Example Book");
         assert_eq!(notes[1].tidied_note, "Second highlight.");
     }
 
@@ -214,7 +817,8 @@ mod tests {
         let file = write_temp(content);
         let notes = parse_clippings(file.path(), &french_config()).unwrap();
         assert_eq!(notes.len(), 1);
-        assert_eq!(notes[0].title, "Livre BOM (Auteur, B)");
+        assert_eq!(notes[0].title, "Livre BOM");
+        assert_eq!(notes[0].author.as_deref(), Some("Auteur, B"));
         assert_eq!(notes[0].tidied_note, "Surlignement avec BOM.");
     }
 
@@ -249,8 +853,8 @@ Deuxième.
         let file = write_temp(content);
         let notes = parse_clippings(file.path(), &french_config()).unwrap();
         assert_eq!(notes.len(), 2);
-        assert_eq!(notes[0].title, "Livre (Auteur)");
-        assert_eq!(notes[1].title, "Livre (Auteur)");
+        assert_eq!(notes[0].title, "Livre");
+        assert_eq!(notes[1].title, "Livre");
     }
 
     #[test]
@@ -266,7 +870,7 @@ Deuxième ligne.\r
         let file = write_temp(content);
         let notes = parse_clippings(file.path(), &french_config()).unwrap();
         assert_eq!(notes.len(), 1);
-        assert_eq!(notes[0].title, "Livre (Auteur)");
+        assert_eq!(notes[0].title, "Livre");
         assert_eq!(notes[0].tidied_note, "Première ligne.\nDeuxième ligne.");
     }
 
@@ -290,6 +894,269 @@ Surlignement utile.
         assert_eq!(notes[0].tidied_note, "Surlignement utile.");
     }
 
+    #[test]
+    fn attaches_note_to_containing_highlight() {
+        let content = "\
+Example Book (Author, A)
+- Your Highlight on page 1 | location 10-12 | Added on Monday, January 1, 2020 10:00:00 AM
+
+Unrelated highlight.
+==========
+Example Book (Author, A)
+- Your Highlight on page 2 | location 20-25 | Added on Monday, January 1, 2020 10:01:00 AM
+
+The highlighted answer.
+==========
+Example Book (Author, A)
+- Your Note on page 2 | location 25 | Added on Monday, January 1, 2020 10:02:00 AM
+
+My question?
+==========
+";
+        let file = write_temp(content);
+        let notes = parse_clippings(file.path(), &english_config()).unwrap();
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes[0].comment, None);
+        assert_eq!(notes[1].tidied_note, "The highlighted answer.");
+        assert_eq!(notes[1].comment.as_deref(), Some("My question?"));
+    }
+
+    #[test]
+    fn keeps_orphan_note_as_its_own_card() {
+        let content = "\
+Example Book (Author, A)
+- Your Highlight on page 1 | location 10-12 | Added on Monday, January 1, 2020 10:00:00 AM
+
+A highlight.
+==========
+Other Book (Author, B)
+- Your Note on page 1 | location 11 | Added on Monday, January 1, 2020 10:02:00 AM
+
+A standalone note.
+==========
+";
+        let file = write_temp(content);
+        let notes = parse_clippings(file.path(), &english_config()).unwrap();
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes[0].comment, None);
+        assert_eq!(notes[1].kind, ClippingKind::Note);
+        assert_eq!(notes[1].tidied_note, "A standalone note.");
+    }
+
+    fn highlight(text: &str, start: u32, end: u32) -> Note {
+        Note {
+            title: "Book".to_owned(),
+            tidied_note: text.to_owned(),
+            location: Some(Location { start, end }),
+            ..Note::default()
+        }
+    }
+
+    #[test]
+    fn dedupe_keeps_latest_of_overlapping_highlights() {
+        let notes = vec![
+            highlight("middle", 100, 102),
+            highlight("other", 200, 201),
+            highlight("start middle end", 99, 104),
+        ];
+        let deduped = dedupe_highlights(notes);
+        assert_eq!(deduped.len(), 2);
+        assert_eq!(deduped[0].tidied_note, "other");
+        assert_eq!(deduped[1].tidied_note, "start middle end");
+    }
+
+    #[test]
+    fn dedupe_matches_extended_text_without_location() {
+        let mut first = highlight("The quick fox", 0, 0);
+        first.location = None;
+        let mut second = highlight("The quick fox jumps", 0, 0);
+        second.location = None;
+        let deduped = dedupe_highlights(vec![first, second]);
+        assert_eq!(deduped.len(), 1);
+        assert_eq!(deduped[0].tidied_note, "The quick fox jumps");
+    }
+
+    #[test]
+    fn dedupe_keeps_highlights_touching_at_a_boundary() {
+        let notes = vec![highlight("first", 100, 101), highlight("second", 101, 103)];
+        assert_eq!(dedupe_highlights(notes).len(), 2);
+    }
+
+    #[test]
+    fn dedupe_keeps_repeated_phrase_elsewhere_in_the_book() {
+        let notes = vec![
+            highlight("Fear", 100, 100),
+            highlight("Fear is the mind-killer.", 500, 502),
+            highlight("Fear is the mind-killer", 99, 99),
+        ];
+        let deduped = dedupe_highlights(notes);
+        let texts: Vec<&str> = deduped
+            .iter()
+            .map(|note| note.tidied_note.as_str())
+            .collect();
+        assert_eq!(
+            texts,
+            ["Fear is the mind-killer.", "Fear is the mind-killer"]
+        );
+    }
+
+    #[test]
+    fn dedupe_does_not_match_text_of_located_and_unlocated_highlights() {
+        let mut unlocated = highlight("The quick fox", 0, 0);
+        unlocated.location = None;
+        let notes = vec![unlocated, highlight("The quick fox jumps", 10, 11)];
+        assert_eq!(dedupe_highlights(notes).len(), 2);
+    }
+
+    #[test]
+    fn dedupe_ignores_other_books() {
+        let mut other = highlight("middle", 100, 102);
+        other.title = "Other book".to_owned();
+        let notes = vec![highlight("middle", 100, 102), other];
+        assert_eq!(dedupe_highlights(notes).len(), 2);
+    }
+
+    #[test]
+    fn dedupe_carries_comment_to_final_version() {
+        let mut original = highlight("middle", 100, 102);
+        original.comment = Some("Why?".to_owned());
+        let notes = vec![original, highlight("start middle end", 99, 104)];
+        let deduped = dedupe_highlights(notes);
+        assert_eq!(deduped.len(), 1);
+        assert_eq!(deduped[0].comment.as_deref(), Some("Why?"));
+    }
+
+    #[test]
+    fn detects_language_of_each_entry() {
+        let content = "\
+Livre (Auteur)
+- Votre surlignement sur la page 1 | emplacement 10-11 | Ajouté le lundi 1 janvier 2020 10:00:00
+
+Premier.
+==========
+Book (Author)
+- Your Highlight on page 2 | location 20-21 | Added on Monday, January 1, 2020 10:00:00 AM
+
+Second.
+==========
+Buch (Autor)
+- Ihre Markierung auf Seite 3 | Position 30-31 | Hinzugefügt am Montag, 1. Januar 2020 10:00:00
+
+Dritte.
+==========
+";
+        let file = write_temp(content);
+        let notes = parse_clippings(file.path(), &french_config()).unwrap();
+        assert_eq!(notes.len(), 3);
+        assert_eq!(notes[0].tidied_note, "Premier.");
+        assert_eq!(notes[1].tidied_note, "Second.");
+        assert_eq!(notes[1].page, Some(2));
+        assert_eq!(notes[2].tidied_note, "Dritte.");
+        assert_eq!(notes[2].location, Some(Location { start: 30, end: 31 }));
+    }
+
+    #[test]
+    fn keeps_body_lines_containing_a_kind_marker() {
+        let content = "\
+本 (著者)
+- 10ページ|位置No. 100-101のハイライト |作成日: 2020年1月1日 水曜日 10:00:00
+
+- 最初のハイライトの例
+- 二番目
+==========
+";
+        let file = write_temp(content);
+        let notes = parse_clippings(file.path(), &french_config()).unwrap();
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].tidied_note, "- 最初のハイライトの例\n- 二番目");
+        assert_eq!(
+            notes[0].location,
+            Some(Location {
+                start: 100,
+                end: 101
+            })
+        );
+    }
+
+    #[test]
+    fn falls_back_to_configured_prefixes() {
+        let config = AppConfig {
+            parser: ParserConfig {
+                bookmark: "- Custom Bookmark".to_owned(),
+                highlight: "- Custom Highlight".to_owned(),
+                note: "- Custom Note".to_owned(),
+                ..ParserConfig::default()
+            },
+            ..AppConfig::default()
+        };
+        let content = "\
+Book (Author)
+- Custom Highlight | location 5-6 | Added whenever
+
+Custom highlight.
+==========
+";
+        let file = write_temp(content);
+        let notes = parse_clippings(file.path(), &config).unwrap();
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].tidied_note, "Custom highlight.");
+        assert_eq!(notes[0].location, Some(Location { start: 5, end: 6 }));
+    }
+
+    #[test]
+    fn detected_entries_keep_configured_ignored_patterns() {
+        let config = AppConfig {
+            parser: ParserConfig {
+                ignored: vec!["<skip me>".to_owned()],
+                ..ParserConfig::default()
+            },
+            ..AppConfig::default()
+        };
+        let content = "\
+Book (Author)
+- Your Highlight on page 2 | location 20-21 | Added on Monday, January 1, 2020 10:00:00 AM
+
+Kept <skip me> dropped
+==========
+";
+        let file = write_temp(content);
+        let notes = parse_clippings(file.path(), &config).unwrap();
+        assert_eq!(notes[0].tidied_note, "Kept");
+    }
+
+    #[test]
+    fn unparseable_date_does_not_abort() {
+        let content = "\
+Book (Author)
+- Your Highlight on page 2 | location 20-21 | Added on Someday
+
+Still imported.
+==========
+";
+        let file = write_temp(content);
+        let notes = parse_clippings(file.path(), &english_config()).unwrap();
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].added_on.as_deref(), Some("Added on Someday"));
+        assert_eq!(notes[0].added_at, None);
+    }
+
+    #[test]
+    fn marks_clippings_sharing_a_location_start() {
+        let mut other_book = highlight("elsewhere", 100, 100);
+        other_book.title = "Other".to_owned();
+        let mut notes = vec![
+            highlight("point", 100, 100),
+            highlight("distinct", 100, 105),
+            highlight("alone", 106, 110),
+            other_book,
+        ];
+        assert_eq!(dedupe_highlights(notes.clone()).len(), 4);
+        mark_shared_starts(&mut notes);
+        let shared: Vec<bool> = notes.iter().map(|note| note.shared_start).collect();
+        assert_eq!(shared, [true, true, false, false]);
+        assert_ne!(notes[0].stable_id(), notes[1].stable_id());
+    }
+
     #[test]
     fn strips_inline_clipping_limit_notice() {
         let content = "\
@@ -1,7 +1,396 @@
+use chrono::NaiveDateTime;
+use std::fmt;
+
+/// Kind of clipping, as announced by the metadata line
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ClippingKind {
+    #[default]
+    Highlight,
+    Note,
+    Bookmark,
+}
+
+impl ClippingKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Highlight => "highlight",
+            Self::Note => "note",
+            Self::Bookmark => "bookmark",
+        }
+    }
+}
+
+/// Location range of a clipping, in Kindle location units
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
 /// Representation of a note
+#[allow(clippy::struct_field_names)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct Note {
-    /// Title of the book
+    /// Title of the book, without its author
     pub title: String,
+    /// Author(s) of the book, multiple authors separated by "; "
+    pub author: Option<String>,
     /// Tidied content of the note
     pub tidied_note: String,
+    /// Personal note attached to this highlight
+    pub comment: Option<String>,
+    /// Kind of clipping (highlight, note or bookmark)
+    pub kind: ClippingKind,
+    /// Page number, when the book has real page numbers
+    pub page: Option<u32>,
+    /// Location range in the book
+    pub location: Option<Location>,
+    /// Raw, localized "Added on" part of the metadata line
+    pub added_on: Option<String>,
+    /// "Added on" timestamp, in the device local time
+    pub added_at: Option<NaiveDateTime>,
+    /// Whether another clipping of the clippings file starts at the same
+    /// location of the book, see [`Note::stable_id`]
+    pub shared_start: bool,
+}
+
+impl Note {
+    /// Date the clipping was added, as "2020-01-31 10:00", falling back to
+    /// the raw localized text when it could not be parsed
+    pub fn date(&self) -> Option<String> {
+        match self.added_at {
+            Some(added_at) => Some(added_at.format("%Y-%m-%d %H:%M").to_string()),
+            None => self.added_on.clone(),
+        }
+    }
+
+    /// Renders a field template with the `{title}`, `{author}`, `{text}`,
+    /// `{comment}`, `{page}`, `{location}`, `{date}` and `{kind}`
+    /// placeholders; unknown placeholders are kept as is
+    pub fn render(&self, template: &str) -> String {
+        fill_placeholders(template, |name| self.placeholder(name))
+    }
+
+    fn placeholder(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "title" => self.title.clone(),
+            "author" => self.author.clone().unwrap_or_default(),
+            "text" => self.tidied_note.clone(),
+            "comment" => self.comment.clone().unwrap_or_default(),
+            "page" => self.page.map(|page| page.to_string()).unwrap_or_default(),
+            "location" => self
+                .location
+                .map(|location| location.to_string())
+                .unwrap_or_default(),
+            "date" => self.date().unwrap_or_default(),
+            "kind" => self.kind.as_str().to_owned(),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Renders tag templates with the `{title_slug}`, `{author_slug}` and
+    /// `{kind}` placeholders. Tags whose placeholders are empty, such as
+    /// `author::{author_slug}` for a book without author, are left out.
+    pub fn tags(&self, templates: &[String]) -> Vec<String> {
+        let values = [
+            ("{title_slug}", slug(&self.title)),
+            (
+                "{author_slug}",
+                slug(self.author.as_deref().unwrap_or_default()),
+            ),
+            ("{kind}", self.kind.as_str().to_owned()),
+        ];
+        let mut tags: Vec<String> = Vec::with_capacity(templates.len());
+        for template in templates {
+            let mut tag = template.split_whitespace().collect::<Vec<_>>().join("_");
+            let mut complete = true;
+            for (placeholder, value) in &values {
+                if tag.contains(placeholder) {
+                    complete &= !value.is_empty();
+                    tag = tag.replace(placeholder, value);
+                }
+            }
+            if complete && !tag.is_empty() && !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+        tags
+    }
+
+    /// Identifier of the clipping, stable when its text is edited or
+    /// extended: hash of the book and location start. Clippings sharing
+    /// their start with another one, like a highlight at 100 and another at
+    /// 100-105, also hash their location end, kind and text, which tells
+    /// them apart. Clippings without a location have none.
+    pub fn stable_id(&self) -> Option<String> {
+        let location = self.location?;
+        let mut hash = Fnv1a::default();
+        hash.write(&self.title);
+        hash.write(self.author.as_deref().unwrap_or_default());
+        hash.write(&location.start.to_string());
+        if self.shared_start {
+            hash.write(&location.end.to_string());
+            hash.write(self.kind.as_str());
+            hash.write(&self.tidied_note);
+        }
+        Some(format!("{:016x}", hash.0))
+    }
+
+    /// Hash of the card content, stable across runs and Rust versions
+    pub fn content_hash(&self) -> String {
+        let mut hash = Fnv1a::default();
+        hash.write(&self.title);
+        hash.write(self.author.as_deref().unwrap_or_default());
+        hash.write(&self.tidied_note);
+        hash.write(self.comment.as_deref().unwrap_or_default());
+        format!("{:016x}", hash.0)
+    }
+}
+
+/// Replaces the `{name}` placeholders of `template` for which `value`
+/// returns some text, in a single pass: placeholders in the values are not
+/// expanded again. Unknown placeholders are kept as is.
+pub fn fill_placeholders(template: &str, value: impl Fn(&str) -> Option<String>) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        rendered.push_str(&rest[..open]);
+        rest = &rest[open..];
+        let filled = rest
+            .find('}')
+            .and_then(|close| Some((close, value(&rest[1..close])?)));
+        if let Some((close, filled)) = filled {
+            rendered.push_str(&filled);
+            rest = &rest[close + 1..];
+        } else {
+            rendered.push('{');
+            rest = &rest[1..];
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Field value as Anki stores it: HTML, with line breaks as `<br>`
+pub fn to_html(text: &str) -> String {
+    let mut html = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '\n' => html.push_str("<br>"),
+            '\r' => {}
+            _ => html.push(c),
+        }
+    }
+    html
+}
+
+/// Lowercase alphanumeric words joined by `-`, usable in an Anki tag
+fn slug(text: &str) -> String {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// 64-bit FNV-1a, chosen over `std::hash` whose output may change between
+/// releases while hashes are persisted
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Fnv1a {
+    fn write(&mut self, text: &str) {
+        // The terminating 0xff keeps ("ab", "c") and ("a", "bc") apart
+        for &byte in text.as_bytes().iter().chain(&[0xff]) {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// Notes shared by the tests of the output modules
+#[cfg(test)]
+pub mod fixtures {
+    use super::{Location, Note};
+
+    /// Highlight of Dune at the `start`-`start + 2` location
+    pub fn dune(start: u32, text: &str) -> Note {
+        Note {
+            title: "Dune".to_owned(),
+            author: Some("Herbert, Frank".to_owned()),
+            tidied_note: text.to_owned(),
+            location: Some(Location {
+                start,
+                end: start + 2,
+            }),
+            ..Note::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(title: &str, text: &str) -> Note {
+        Note {
+            title: title.to_owned(),
+            tidied_note: text.to_owned(),
+            ..Note::default()
+        }
+    }
+
+    #[test]
+    fn formats_location_ranges() {
+        assert_eq!(Location { start: 10, end: 12 }.to_string(), "10-12");
+        assert_eq!(Location { start: 10, end: 10 }.to_string(), "10");
+    }
+
+    #[test]
+    fn formats_date_or_falls_back_to_raw_text() {
+        let mut dated = note("Book", "Highlight");
+        dated.added_on = Some("Added on Someday".to_owned());
+        assert_eq!(dated.date().as_deref(), Some("Added on Someday"));
+        dated.added_at = chrono::NaiveDate::from_ymd_opt(2020, 1, 31)
+            .and_then(|date| date.and_hms_opt(10, 0, 59));
+        assert_eq!(dated.date().as_deref(), Some("2020-01-31 10:00"));
+    }
+
+    #[test]
+    fn renders_field_templates() {
+        let mut quoted = note("Dune", "Fear is the {mind}-killer.");
+        quoted.author = Some("Herbert, Frank".to_owned());
+        quoted.page = Some(12);
+        quoted.location = Some(Location { start: 10, end: 12 });
+        assert_eq!(
+            quoted.render("{text} ({author}, {title}, p. {page}, loc. {location}){comment}"),
+            "Fear is the {mind}-killer. (Herbert, Frank, Dune, p. 12, loc. 10-12)"
+        );
+        quoted.title = "{author} on {text}".to_owned();
+        assert_eq!(
+            quoted.render("{title} by {author}"),
+            "{author} on {text} by Herbert, Frank"
+        );
+        assert_eq!(
+            quoted.render("{kind}: {unknown} {date} {"),
+            "highlight: {unknown}  {"
+        );
+    }
+
+    #[test]
+    fn renders_tag_templates() {
+        let mut tagged = note("The Hobbit: There and Back Again", "Highlight");
+        tagged.author = Some("Tolkien, J. R. R.".to_owned());
+        tagged.kind = ClippingKind::Note;
+        let templates = [
+            "kindle",
+            "book::{title_slug}",
+            "author::{author_slug}",
+            "{kind}",
+            "kindle",
+        ]
+        .map(str::to_owned);
+        assert_eq!(
+            tagged.tags(&templates),
+            [
+                "kindle",
+                "book::the-hobbit-there-and-back-again",
+                "author::tolkien-j-r-r",
+                "note"
+            ]
+        );
+    }
+
+    #[test]
+    fn drops_tags_with_empty_placeholders() {
+        let templates = ["author::{author_slug}", "my books"].map(str::to_owned);
+        assert_eq!(
+            note("Élan vital", "Highlight").tags(&templates),
+            ["my_books"]
+        );
+    }
+
+    #[test]
+    fn converts_field_values_to_html() {
+        assert_eq!(
+            to_html("Q&A <b>\r\nnext line"),
+            "Q&amp;A &lt;b&gt;<br>next line"
+        );
+    }
+
+    #[test]
+    fn content_hash_is_stable() {
+        assert_eq!(note("Book", "Highlight").content_hash(), "4d7791d047755f98");
+    }
+
+    #[test]
+    fn stable_id_of_shared_start_depends_on_the_whole_clipping() {
+        let mut point = note("Book", "Highlight");
+        point.location = Some(Location { start: 10, end: 10 });
+        let mut range = note("Book", "Another highlight");
+        range.location = Some(Location { start: 10, end: 14 });
+        let unshared = point.stable_id();
+        assert_eq!(unshared, range.stable_id());
+
+        point.shared_start = true;
+        range.shared_start = true;
+        assert_ne!(point.stable_id(), unshared);
+        assert_ne!(point.stable_id(), range.stable_id());
+        range.location = point.location;
+        assert_ne!(point.stable_id(), range.stable_id());
+    }
+
+    #[test]
+    fn stable_id_only_depends_on_book_and_location_start() {
+        let mut short = note("Book", "Highlight");
+        assert_eq!(short.stable_id(), None);
+        short.location = Some(Location { start: 10, end: 12 });
+        let mut extended = note("Book", "Highlight, extended");
+        extended.location = Some(Location { start: 10, end: 14 });
+        assert_eq!(short.stable_id(), extended.stable_id());
+        assert_eq!(short.stable_id().map(|id| id.len()), Some(16));
+
+        extended.location = Some(Location { start: 11, end: 14 });
+        assert_ne!(short.stable_id(), extended.stable_id());
+        extended.location = short.location;
+        extended.title = "Other book".to_owned();
+        assert_ne!(short.stable_id(), extended.stable_id());
+    }
+
+    #[test]
+    fn content_hash_separates_fields() {
+        assert_ne!(
+            note("Book", "AHighlight").content_hash(),
+            note("BookA", "Highlight").content_hash()
+        );
+    }
+
+    #[test]
+    fn content_hash_ignores_metadata() {
+        let mut moved = note("Book", "Highlight");
+        moved.page = Some(3);
+        assert_eq!(
+            moved.content_hash(),
+            note("Book", "Highlight").content_hash()
+        );
+    }
 }